@@ -0,0 +1,31 @@
+use super::{inspect, purge, warm};
+use crate::util::bearer;
+use actix_web::dev::Service;
+use actix_web::web;
+use futures_util::future::{ready, Either};
+
+/// Registers the admin API below `/admin/v1`, requiring `token` as bearer token
+pub fn configure(token: &str, cfg: &mut web::ServiceConfig) {
+    let token = token.to_owned();
+
+    cfg.service(
+        web::scope("/admin/v1")
+            .wrap_fn(move |req, srv| {
+                if bearer::is_authorized(req.headers(), &token) {
+                    Either::Left(srv.call(req))
+                } else {
+                    Either::Right(ready(Ok(req.into_response(bearer::unauthorized()))))
+                }
+            })
+            .route("/entries", web::get().to(inspect::entries))
+            .route("/{entry}/files", web::get().to(inspect::files))
+            .route("/{entry}/f/{filename}", web::get().to(inspect::file))
+            .route(
+                "/{entry}/f/{filename}/verify",
+                web::post().to(inspect::verify),
+            )
+            .route("/{entry}/warm", web::post().to(warm::warm))
+            .route("/{entry}/purge", web::post().to(purge::purge))
+            .route("/{entry}/f/{filename}", web::delete().to(purge::purge_file)),
+    );
+}
@@ -1,111 +1,1103 @@
-use super::download::{Downloader, DownloadError};
-use crate::config::Config;
-use crate::digest::Digest;
+use super::client;
+use super::compression::{self, CompressionRules, VARIANTS_DIR};
+use super::download::{check_status, DownloadError, Downloader};
+use super::headers::HeaderRules;
+use super::index::{self, DigestStore};
+use super::limits::NodeLimits;
+use super::listing;
+use super::negative::NegativeCache;
+use super::origins::Origins;
+use super::shutdown::SHUTDOWN;
+use crate::config::{CacheConfig, Config, ConfigError, Encoding, Entry};
+use crate::digest::{Digest, DigestError};
+use crate::metrics;
+use crate::util::file_name;
+use crate::util::named_file::NamedFile;
+use crate::util::throttle::Throttle;
+use actix_http::body::SizedStream;
+use actix_web::dev::{BodyEncoding, HttpResponseBuilder};
+use actix_web::http::header::{self, HttpDate};
+use actix_web::http::ContentEncoding;
+use actix_web::web::Bytes;
+use actix_web::{web, HttpResponse};
+use futures_util::{future, stream};
 use globset::GlobSet;
 use reqwest::Client;
+use serde::Serialize;
 use std::collections::HashMap;
 use std::fs;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use std::path::{Path, PathBuf};
-use thiserror::Error;
-use tokio::sync::{RwLock, Semaphore};
-use url::Url;
+use std::sync::Arc;
+use std::time::SystemTime;
+use tokio::sync::{Mutex, RwLock};
+use url::{Host, Url};
+
+/// `Cache-Control` directive asking a cache node to answer from its cache only, it responds
+/// with `504 Gateway Timeout` if the file is not cached
+pub const ONLY_IF_CACHED: &str = "only-if-cached";
+
+/// Header telling whether the response was served from the cache
+pub const X_CACHE: &str = "x-cache";
 
 pub struct Cache {
     client: Client,
+    node_client: Client,
     pub name: String,
-    base: Url,
+    origins: Origins,
+    parents: Option<Origins>,
+    peers: Option<Origins>,
     patterns: GlobSet,
+    headers: HeaderRules,
+    compression: Arc<CompressionRules>,
     path: PathBuf,
-    items: RwLock<HashMap<String, Digest>>,
-    // work_sem: Semaphore,
+    store: Arc<DigestStore>,
+    throttles: std::sync::RwLock<Throttles>,
+    max_ranges: usize,
+    head_fill: bool,
+    forward_forbidden: bool,
+    items: Arc<RwLock<HashMap<String, Digest>>>,
+    variants: Arc<RwLock<HashMap<String, Vec<Digest>>>>,
+    stats: Arc<std::sync::Mutex<HashMap<String, AccessStats>>>,
+    negative: NegativeCache,
+    // TODO Limit the number of parallel downloads
+    in_work: Arc<WorkLocks>,
+}
+
+/// Bandwidth limits of the fills and responses of an entry, together with the ones of the
+/// node
+struct Throttles {
+    fill: Throttle,
+    serve: Throttle,
+    connection_rate: Option<u64>,
+}
+
+impl Throttles {
+    fn new(entry: &Entry, config: &CacheConfig, limits: &NodeLimits) -> Self {
+        Throttles {
+            fill: Throttle::default()
+                .with_bucket(limits.fill.clone())
+                .with_rate(entry.bandwidth.fill),
+            serve: Throttle::default()
+                .with_bucket(limits.serve.clone())
+                .with_rate(entry.bandwidth.serve),
+            connection_rate: entry.bandwidth.connection.or(config.bandwidth.connection),
+        }
+    }
 
-    // TODO Make use of in_work as a Semaphore to limit the number of parallel downloads
-    // in_work: RwLock<Vec<String>>,
+    /// Throttle of a single response, with a bucket of its own for the connection rate
+    fn serve(&self) -> Throttle {
+        self.serve.clone().with_rate(self.connection_rate)
+    }
+}
+
+/// Locks of the files being downloaded, compressed or purged, by file name
+#[derive(Default)]
+pub struct WorkLocks(std::sync::Mutex<HashMap<String, Arc<Mutex<()>>>>);
+
+impl WorkLocks {
+    fn get(&self, name: &str) -> Arc<Mutex<()>> {
+        self.0
+            .lock()
+            .unwrap()
+            .entry(name.to_owned())
+            .or_default()
+            .clone()
+    }
+
+    fn release(&self, name: &str, lock: Arc<Mutex<()>>) {
+        let mut locks = self.0.lock().unwrap();
+        // Only the map and this call still hold the lock
+        if Arc::strong_count(&lock) == 2 {
+            locks.remove(name);
+        }
+    }
+}
+
+/// Files loaded into a cache, handed on to the cache rebuilt for a reloaded config
+struct Loaded {
+    store: Arc<DigestStore>,
+    items: Arc<RwLock<HashMap<String, Digest>>>,
+    variants: Arc<RwLock<HashMap<String, Vec<Digest>>>>,
 }
 
 impl Cache {
-    pub fn new(name: &str, config: &Config) -> Self {
-        let entry = &config.entries[name];
+    /// Creates the cache of an entry and loads its files from disk
+    pub fn new(name: &str, config: &Config, limits: &NodeLimits) -> Result<Self, ConfigError> {
+        Self::build(name, config, limits, None, None)
+    }
+
+    /// Creates the cache for the reloaded config of its entry. The loaded files are kept if
+    /// its directory and digest store stay the same, instead of reading and verifying them
+    /// again. The work locks are shared, so downloads still running on this cache are not
+    /// raced by new ones of the same files, and so are the access stats and the remembered
+    /// origin failures. Call `forget_unmatched` on the result.
+    pub fn rebuild(&self, config: &Config, limits: &NodeLimits) -> Result<Self, ConfigError> {
+        let loaded = if self.keeps_files(config) {
+            Some(Loaded {
+                store: self.store.clone(),
+                items: self.items.clone(),
+                variants: self.variants.clone(),
+            })
+        } else {
+            None
+        };
+
+        Self::build(&self.name, config, limits, loaded, Some(self))
+    }
+
+    /// Whether a rebuild for `config` keeps the loaded files, as their directory and digest
+    /// store are unchanged
+    pub fn keeps_files(&self, config: &Config) -> bool {
+        Path::new(&config.cache.root_path).join(&self.name) == self.path
+            && config.cache.index == self.store.is_index()
+    }
+
+    /// Applies changed bandwidth limits of the node to the kept cache
+    pub fn set_limits(&self, entry: &Entry, config: &CacheConfig, limits: &NodeLimits) {
+        *self.throttles.write().unwrap() = Throttles::new(entry, config, limits);
+    }
+
+    /// Drops kept files that no longer match the patterns of the entry
+    pub async fn forget_unmatched(&self) {
+        let mut items = self.items.write().await;
+        items.retain(|name, _| self.patterns.is_match(name));
+        self.variants
+            .write()
+            .await
+            .retain(|name, _| items.contains_key(name));
+    }
+
+    fn build(
+        name: &str,
+        config: &Config,
+        limits: &NodeLimits,
+        loaded: Option<Loaded>,
+        previous: Option<&Cache>,
+    ) -> Result<Self, ConfigError> {
+        let entry = config
+            .entries
+            .get(name)
+            .ok_or_else(|| ConfigError::UnknownEntry(name.to_owned()))?;
         let path = Path::new(&config.cache.root_path).join(name);
-        fs::create_dir_all(&path).unwrap();
-        let patterns = entry.get_globset().unwrap();
+        fs::create_dir_all(&path)?;
+        let origins = Origins::new(entry)?;
+        let parents = node_urls(&config.cache.parents)?;
+        let parents = if parents.is_empty() {
+            None
+        } else {
+            Some(Origins::nodes(&parents, name, entry)?)
+        };
+
+        let mut peers = node_urls(&config.cache.peers)?;
+        let node_url = config
+            .cache
+            .node_url
+            .as_deref()
+            .map(Url::parse)
+            .transpose()?;
+        peers.retain(|url| !is_self(url, node_url.as_ref(), &config.cache.bind));
+        let peers = if peers.is_empty() {
+            None
+        } else {
+            Some(Origins::nodes(&peers, name, entry)?)
+        };
+        let patterns = entry.get_globset()?;
+        let headers = HeaderRules::new(&entry.headers, &config.cache.node_id_header)?;
+        let compression = CompressionRules::new(&entry.compression)?;
 
-        let items = preprocess_existing(&path, &patterns);
+        let loaded = match loaded {
+            Some(loaded) => loaded,
+            None => {
+                let store = DigestStore::open(&path, &config.cache)?;
+                let (items, variants) = match store {
+                    DigestStore::Sidecar => {
+                        let items = preprocess_existing(&path, &patterns, &store)?;
+                        let variants = preprocess_variants(&path, &items, &store)?;
+                        (items, variants)
+                    }
+                    DigestStore::Index(_) => preprocess_index(&path, &patterns, &store)?,
+                };
 
-        let max_parallel_downloads = 2;
+                Loaded {
+                    store: Arc::new(store),
+                    items: Arc::new(RwLock::new(items)),
+                    variants: Arc::new(RwLock::new(variants)),
+                }
+            }
+        };
 
-        Cache {
-            client: Client::new(),
+        Ok(Cache {
+            client: client::build(&entry.origin)?,
+            node_client: client::build_node(&entry.origin)?,
             name: name.to_owned(),
-            base: Url::parse(&entry.base_url).unwrap(),
+            origins,
+            parents,
+            peers,
             path,
+            store: loaded.store,
+            throttles: std::sync::RwLock::new(Throttles::new(entry, &config.cache, limits)),
+            max_ranges: config.cache.max_ranges,
+            head_fill: config.cache.head_fill,
+            forward_forbidden: entry.origin.forward_forbidden,
             patterns,
-            items: RwLock::new(items),
-            // work_sem: Semaphore::new(max_parallel_downloads),
-            // in_work: RwLock::new(Vec::new()),
-        }
+            headers,
+            compression: Arc::new(compression),
+            items: loaded.items,
+            variants: loaded.variants,
+            stats: previous.map_or_else(Arc::default, |cache| cache.stats.clone()),
+            negative: match previous {
+                Some(cache) => cache.negative.reconfigure(&entry.negative_cache),
+                None => NegativeCache::new(&entry.negative_cache),
+            },
+            in_work: previous.map_or_else(Arc::default, |cache| cache.in_work.clone()),
+        })
     }
 
     pub async fn get(&self, filename: &str) -> CacheResult {
-        if !self.patterns.is_match(filename) {
+        let requests = |result| metrics::CACHE_REQUESTS.with_label_values(&[&self.name, result]);
+
+        if !file_name::is_valid(filename) || !self.patterns.is_match(filename) {
+            requests("not_found").inc();
             return CacheResult::NotFound;
         }
 
-        if let Some(digest) = self.items.read().await.get(filename).cloned() {
-            return CacheResult::Ok(digest);
+        if let Some(digest) = self.lookup(filename).await {
+            requests("hit").inc();
+            self.record_access(filename);
+            return CacheResult::Ok(digest, Outcome::Hit);
+        }
+
+        let res = self.cache(filename).await;
+        match res {
+            CacheResult::Ok(..) => {
+                requests("miss").inc();
+                self.record_access(filename);
+            }
+            CacheResult::DownloadError(_) => requests("error").inc(),
+            CacheResult::NotFound => requests("not_found").inc(),
+        }
+        res
+    }
+
+    /// Downloads the file ahead of demand if it is not cached yet. Unlike `get` it does not
+    /// count as a request of the file in the metrics and access stats.
+    pub async fn fill(&self, filename: &str) -> CacheResult {
+        if !self.patterns.is_match(filename) {
+            return CacheResult::NotFound;
         }
 
         self.cache(filename).await
     }
 
+    /// Returns the digest of the file if it is cached, never downloads
+    pub async fn lookup(&self, filename: &str) -> Option<Digest> {
+        self.items.read().await.get(filename).cloned()
+    }
+
+    /// Returns the digests of all cached files, sorted by file name
+    pub async fn list(&self) -> Vec<Digest> {
+        let mut res: Vec<Digest> = self.items.read().await.values().cloned().collect();
+        res.sort_by(|a, b| a.file_name.cmp(&b.file_name));
+        res
+    }
+
+    /// Returns the digests of the compressed variants of a cached file
+    pub async fn variants(&self, filename: &str) -> Vec<Digest> {
+        self.variants
+            .read()
+            .await
+            .get(filename)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Returns how often and when a file was last requested since the node started
+    pub fn access_stats(&self, filename: &str) -> AccessStats {
+        self.stats
+            .lock()
+            .unwrap()
+            .get(filename)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    fn record_access(&self, filename: &str) {
+        let mut stats = self.stats.lock().unwrap();
+        let stats = stats.entry(filename.to_owned()).or_default();
+        stats.requests += 1;
+        stats.last_access = Some(HttpDate::from(SystemTime::now()).to_string());
+    }
+
+    /// Downloads the file, concurrent calls for the same file wait for the first download.
+    /// Recent origin failures for the file are returned without downloading.
     pub async fn cache(&self, name: &str) -> CacheResult {
-        let url = self.base.join(name).unwrap();
+        if !file_name::is_valid(name) {
+            return CacheResult::NotFound;
+        }
+
+        let lock = self.in_work.get(name);
+
+        let res = {
+            let _guard = lock.lock().await;
+
+            if let Some(digest) = self.lookup(name).await {
+                CacheResult::Ok(digest, Outcome::Hit)
+            } else if let Some(status) = self.negative.get(name) {
+                CacheResult::DownloadError(DownloadError::OriginStatus(status))
+            } else {
+                self.download(name).await
+            }
+        };
+
+        self.in_work.release(name, lock);
+        res
+    }
+
+    /// Removes the file and its variants, waiting for a running download of it to finish
+    /// first. Returns whether the file was cached.
+    pub async fn purge(&self, name: &str) -> io::Result<bool> {
+        let lock = self.in_work.get(name);
+
+        let res = {
+            let _guard = lock.lock().await;
+
+            let digest = self.items.write().await.remove(name);
+            let variants = self.variants.write().await.remove(name);
+            self.stats.lock().unwrap().remove(name);
+            self.negative.remove(name);
+
+            variants
+                .unwrap_or_default()
+                .iter()
+                .try_for_each(|variant| self.store.remove(variant))
+                .and_then(|_| digest.as_ref().map_or(Ok(()), |d| self.store.remove(d)))
+                .map(|_| digest.is_some())
+        };
+
+        self.in_work.release(name, lock);
+
+        if let Ok(true) = res {
+            log::info!("Purged {}/{}", self.name, name);
+        }
+        res
+    }
+
+    /// Purges all cached files whose digest matches the predicate, returning their names
+    pub async fn purge_where<F>(&self, predicate: F) -> io::Result<Vec<String>>
+    where
+        F: Fn(&Digest) -> bool,
+    {
+        let names: Vec<String> = self
+            .items
+            .read()
+            .await
+            .iter()
+            .filter(|(_, digest)| predicate(digest))
+            .map(|(name, _)| name.clone())
+            .collect();
+
+        let mut purged = Vec::new();
+        for name in names {
+            if self.purge(&name).await? {
+                purged.push(name);
+            }
+        }
+
+        Ok(purged)
+    }
+
+    async fn download(&self, name: &str) -> CacheResult {
         let path = self.path.join(name);
 
-        match Downloader::new(&self.client, url, &path).download().await {
-            Ok(digest) => {
-                digest.write(&self.path).unwrap();
+        if SHUTDOWN.is_stopping() {
+            return CacheResult::DownloadError(DownloadError::ShuttingDown);
+        }
+
+        let timer = metrics::DownloadTimer::start(&self.name);
 
+        let mut res = None;
+
+        if let Some(ref peers) = self.peers {
+            res = self.fill_from_peers(peers, name, &path).await.map(Ok);
+        }
+
+        if let Some(parents) = self.parents.as_ref().filter(|_| res.is_none()) {
+            let parent_res = parents
+                .request(name, |url| {
+                    let path = &path;
+                    async move {
+                        self.downloader(&self.node_client, url, path)
+                            .with_etag_verification()
+                            .download()
+                            .await
+                    }
+                })
+                .await;
+
+            match parent_res {
+                Ok(digest) => res = Some(Ok(digest)),
+                Err(err) => log::info!(
+                    "Failed to fill {}/{} from parents, trying the origin: {}",
+                    self.name,
+                    name,
+                    err
+                ),
+            }
+        }
+
+        let res = match res {
+            Some(res) => res,
+            None => {
+                self.origins
+                    .request(name, |url| {
+                        let path = &path;
+                        async move { self.downloader(&self.client, url, path).download().await }
+                    })
+                    .await
+            }
+        };
+
+        drop(timer);
+
+        match res {
+            Ok(digest) => {
                 let mut items = self.items.write().await;
                 items.insert(name.to_owned(), digest.clone());
+                self.spawn_compress(name, &digest);
 
-                CacheResult::Ok(digest)
+                CacheResult::Ok(digest, Outcome::Miss)
             }
             Err(err) => {
-                log::error!("Download error: {:?}", err);
+                self.remember_failure(name, &err);
                 CacheResult::DownloadError(err)
             }
         }
     }
+
+    /// Asks all peers at once whether they hold the file and downloads it from the first
+    /// one that does, verifying it against the hash of the peer
+    async fn fill_from_peers(&self, peers: &Origins, name: &str, path: &Path) -> Option<Digest> {
+        let probes: Vec<_> = peers
+            .available()
+            .into_iter()
+            .filter_map(|peer| Some((peer, peer.url.join(name).ok()?)))
+            .map(|(peer, url)| {
+                Box::pin(async move {
+                    let req = self
+                        .node_client
+                        .head(url.clone())
+                        .header(header::CACHE_CONTROL.as_str(), ONLY_IF_CACHED);
+
+                    match req.send().await {
+                        Ok(resp) => resp.error_for_status().map(|_| (peer, url)),
+                        Err(err) => {
+                            peers.failed(peer);
+                            Err(err)
+                        }
+                    }
+                })
+            })
+            .collect();
+
+        if probes.is_empty() {
+            return None;
+        }
+
+        let ((peer, url), _) = future::select_ok(probes).await.ok()?;
+        log::debug!("Filling {}/{} from peer {}", self.name, name, url);
+
+        let res = self
+            .downloader(&self.node_client, url, path)
+            .with_etag_verification()
+            .with_request_header(reqwest::header::CACHE_CONTROL, ONLY_IF_CACHED)
+            .download()
+            .await;
+
+        match res {
+            Ok(digest) => {
+                peer.succeeded();
+                Some(digest)
+            }
+            Err(err) => {
+                if err.is_origin_failure() {
+                    peers.failed(peer);
+                }
+                log::info!("Failed to fill {}/{} from peer: {}", self.name, name, err);
+                None
+            }
+        }
+    }
+
+    fn remember_failure(&self, name: &str, err: &DownloadError) {
+        if let Some(status) = err.origin_status() {
+            self.negative.insert(name, status);
+        }
+    }
+
+    /// Creates the compressed variants of a freshly downloaded file in the background, so
+    /// the request that filled it is not held up. The work lock of the file is taken, a
+    /// purge waits for the variants and a file purged in the meantime is skipped.
+    fn spawn_compress(&self, name: &str, digest: &Digest) {
+        if self.compression.encodings().is_empty() || !self.compression.applies_to(digest) {
+            return;
+        }
+
+        let (name, digest) = (name.to_owned(), digest.clone());
+        let (rules, root, store) = (
+            self.compression.clone(),
+            self.path.clone(),
+            self.store.clone(),
+        );
+        let (items, variants, in_work) = (
+            self.items.clone(),
+            self.variants.clone(),
+            self.in_work.clone(),
+        );
+
+        actix_rt::spawn(async move {
+            let lock = in_work.get(&name);
+            {
+                let _guard = lock.lock().await;
+
+                let current = items.read().await.get(&name).map(Digest::hash);
+                if current == Some(digest.hash()) {
+                    let res = compress(&rules, &digest, &root, &store).await;
+                    variants.write().await.insert(name.clone(), res);
+                }
+            }
+            in_work.release(&name, lock);
+        });
+    }
+
+    /// Prepares the response for the given digest, choosing one of the compressed variants
+    /// if the client accepts it.
+    pub async fn serve(
+        &self,
+        digest: &Digest,
+        outcome: Outcome,
+        accept_encoding: Option<&str>,
+    ) -> io::Result<NamedFile> {
+        let (file, mut headers, encoding) = self.select(digest, accept_encoding).await;
+        headers.extend(cache_headers(digest, outcome));
+        let file = file
+            .serve(headers)?
+            .set_max_ranges(self.max_ranges)
+            .set_bytes_counter(metrics::SERVED_BYTES.with_label_values(&[&self.name]))
+            .set_throttle(self.throttles.read().unwrap().serve());
+
+        if let Some(encoding) = encoding {
+            Ok(file.set_content_encoding(encoding))
+        } else {
+            Ok(file)
+        }
+    }
+
+    /// Prepares the response to a `HEAD` request from the digest without touching the file
+    pub async fn head(
+        &self,
+        digest: &Digest,
+        outcome: Outcome,
+        accept_encoding: Option<&str>,
+    ) -> HttpResponse {
+        let (file, mut headers, _) = self.select(digest, accept_encoding).await;
+        headers.extend(cache_headers(digest, outcome));
+
+        let mut resp = HttpResponse::Ok();
+        resp.encoding(ContentEncoding::Identity);
+
+        if file.content_type != "unknown" {
+            resp.header(header::CONTENT_TYPE, file.content_type.as_str());
+        } else {
+            resp.header(header::CONTENT_TYPE, "application/octet-stream");
+        }
+
+        for (name, value) in headers.iter() {
+            resp.header(name.as_str(), value.as_str());
+        }
+
+        resp.set(header::ETag(file.etag()));
+        if let Some(last_modified) = file.last_modified() {
+            resp.set(header::LastModified(last_modified));
+        }
+
+        resp.header(header::ACCEPT_RANGES, "bytes");
+        head_body(&mut resp, file.size)
+    }
+
+    /// Answers a `HEAD` request for a file that is not cached by asking the origin
+    pub async fn head_origin(&self, name: &str) -> Result<HttpResponse, DownloadError> {
+        if let Some(status) = self.negative.get(name) {
+            return Err(DownloadError::OriginStatus(status));
+        }
+
+        let origin = self
+            .origins
+            .request(name, |url| async move {
+                check_status(self.client.head(url).send().await?)
+            })
+            .await
+            .inspect_err(|err| self.remember_failure(name, err))?;
+        let headers = origin.headers();
+
+        let mut resp = HttpResponse::Ok();
+        resp.encoding(ContentEncoding::Identity);
+        resp.header(X_CACHE, Outcome::Miss.x_cache());
+
+        for name in [
+            header::CONTENT_TYPE,
+            header::LAST_MODIFIED,
+            header::ACCEPT_RANGES,
+        ]
+        .iter()
+        {
+            if let Some(value) = headers.get(name).and_then(|v| v.to_str().ok()) {
+                resp.header(name.as_str(), value);
+            }
+        }
+
+        for (name, value) in self.headers.apply(&self.headers.collect(headers)) {
+            resp.header(name.as_str(), value.as_str());
+        }
+
+        let length = headers
+            .get(header::CONTENT_LENGTH)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse().ok());
+
+        if let Some(length) = length {
+            Ok(head_body(&mut resp, length))
+        } else {
+            Ok(resp.finish())
+        }
+    }
+
+    /// Chooses the representation of `digest` to serve, returning its digest, the headers to
+    /// send and the content encoding, `None` leaving it to the `Compress` middleware.
+    async fn select(
+        &self,
+        digest: &Digest,
+        accept_encoding: Option<&str>,
+    ) -> (Digest, Vec<(String, String)>, Option<ContentEncoding>) {
+        let mut headers = self.headers.apply(&digest.headers);
+
+        if !self.compression.applies_to(digest) {
+            return (digest.clone(), headers, Some(ContentEncoding::Identity));
+        }
+
+        headers.push(("vary".to_owned(), "Accept-Encoding".to_owned()));
+
+        let variant = self
+            .variants
+            .read()
+            .await
+            .get(&digest.file_name)
+            .and_then(|variants| {
+                let available: Vec<Encoding> = variants.iter().filter_map(|v| v.encoding).collect();
+                let encoding = compression::negotiate(accept_encoding?, &available)?;
+                variants
+                    .iter()
+                    .find(|v| v.encoding == Some(encoding))
+                    .cloned()
+            });
+
+        if let Some(variant) = variant {
+            let encoding = variant.encoding.unwrap();
+            headers.push(("content-encoding".to_owned(), encoding.as_str().to_owned()));
+            (variant, headers, Some(ContentEncoding::Identity))
+        } else if self.compression.on_the_fly() {
+            (digest.clone(), headers, None)
+        } else {
+            (digest.clone(), headers, Some(ContentEncoding::Identity))
+        }
+    }
+
+    /// Fetches the directory listing at the base URL and returns all linked files that
+    /// match the patterns of the entry
+    pub async fn list_origin(&self) -> Result<Vec<String>, DownloadError> {
+        let html = self
+            .origins
+            .request("", |url| async move {
+                let resp = check_status(self.client.get(url).send().await?)?;
+                Ok(resp.text().await?)
+            })
+            .await?;
+
+        Ok(listing::parse(&html)
+            .into_iter()
+            .filter(|name| self.patterns.is_match(name))
+            .collect())
+    }
+
+    /// Updates the gauges describing the contents of the cache
+    pub async fn update_metrics(&self) {
+        let items = self.items.read().await;
+        let variants = self.variants.read().await;
+
+        let size: u64 = items
+            .values()
+            .chain(variants.values().flatten())
+            .map(|d| d.size)
+            .sum();
+
+        metrics::CACHED_FILES
+            .with_label_values(&[&self.name])
+            .set(items.len() as i64);
+        metrics::CACHE_SIZE
+            .with_label_values(&[&self.name])
+            .set(size as i64);
+    }
+
+    /// Downloader using `client`, counting the fetched bytes and stopping at the shutdown
+    /// deadline
+    fn downloader<'a>(&'a self, client: &'a Client, url: Url, path: &'a Path) -> Downloader<'a> {
+        Downloader::new(client, &self.headers, &self.store, url, path)
+            .with_bytes_counter(metrics::FETCHED_BYTES.with_label_values(&[&self.name]))
+            .with_deadline(SHUTDOWN.deadline())
+            .with_throttle(self.throttles.read().unwrap().fill.clone())
+    }
+
+    pub fn is_match(&self, filename: &str) -> bool {
+        self.patterns.is_match(filename)
+    }
+
+    pub fn head_fill(&self) -> bool {
+        self.head_fill
+    }
+
+    pub fn forward_forbidden(&self) -> bool {
+        self.forward_forbidden
+    }
+}
+
+/// Creates the compressed variants of a file
+async fn compress(
+    rules: &CompressionRules,
+    digest: &Digest,
+    root: &Path,
+    store: &Arc<DigestStore>,
+) -> Vec<Digest> {
+    let mut res = Vec::new();
+
+    for &encoding in rules.encodings() {
+        let digest = digest.clone();
+        let root = root.to_owned();
+        let store = store.clone();
+
+        let create = move || compression::create_variant(&digest, encoding, &root, &store);
+        match web::block(create).await {
+            Ok(Some(variant)) => res.push(variant),
+            Ok(None) => {}
+            Err(err) => log::error!("Failed to compress with {}: {}", encoding.as_str(), err),
+        }
+    }
+
+    res
 }
 
-#[derive(Error, Debug)]
-enum CacheError {}
+/// Requests for a cached file since the node started
+#[derive(Serialize, Clone, Debug, Default)]
+pub struct AccessStats {
+    pub requests: u64,
+    /// Time of the last request as HTTP date
+    pub last_access: Option<String>,
+}
 
+// Results are short lived and mostly `Ok`, boxing the digest would not gain anything
+#[allow(clippy::large_enum_variant)]
 #[derive(Debug)]
 pub enum CacheResult {
-    Ok(Digest),
+    Ok(Digest, Outcome),
     DownloadError(DownloadError),
-    NotCached { redirect: Url, in_work: bool },
     NotFound,
 }
 
-fn preprocess_existing<P: AsRef<Path>>(root: P, glob: &GlobSet) -> HashMap<String, Digest> {
+/// How a request was answered with respect to the cache
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Outcome {
+    /// Served from the cache
+    Hit,
+    /// Not served from the cache, e.g. downloaded first, forwarded to the origin or failed
+    Miss,
+}
+
+impl Outcome {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Outcome::Hit => "hit",
+            Outcome::Miss => "miss",
+        }
+    }
+
+    /// Value of the `X-Cache` header
+    pub fn x_cache(self) -> &'static str {
+        match self {
+            Outcome::Hit => "HIT",
+            Outcome::Miss => "MISS",
+        }
+    }
+}
+
+/// `X-Cache` and `Age` of a response served from the cache
+fn cache_headers(digest: &Digest, outcome: Outcome) -> Vec<(String, String)> {
+    let mut res = vec![(X_CACHE.to_owned(), outcome.x_cache().to_owned())];
+
+    let age = match outcome {
+        Outcome::Hit => digest.age(),
+        Outcome::Miss => Some(0),
+    };
+    if let Some(age) = age {
+        res.push(("age".to_owned(), age.to_string()));
+    }
+
+    res
+}
+
+/// Parses the URLs of other cache nodes
+fn node_urls(urls: &[String]) -> Result<Vec<Url>, ConfigError> {
+    Ok(urls
+        .iter()
+        .map(|url| Url::parse(url))
+        .collect::<Result<_, _>>()?)
+}
+
+/// Whether `url` points to this node, either its `node_url` or, without one, the address
+/// it is bound to. A node bound to all interfaces is reached through any loopback address.
+/// Host names are compared as configured, without resolving them.
+fn is_self(url: &Url, node_url: Option<&Url>, bind: &str) -> bool {
+    if let Some(node_url) = node_url {
+        return url.host() == node_url.host()
+            && url.port_or_known_default() == node_url.port_or_known_default();
+    }
+
+    let (host, port) = match (url.host(), url.port_or_known_default()) {
+        (Some(host), Some(port)) => (host, port),
+        _ => return false,
+    };
+    let ip = match host {
+        Host::Ipv4(ip) => Some(IpAddr::V4(ip)),
+        Host::Ipv6(ip) => Some(IpAddr::V6(ip)),
+        Host::Domain("localhost") => Some(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+        Host::Domain(_) => None,
+    };
+
+    match (bind.parse::<SocketAddr>(), ip) {
+        (Ok(bind), Some(ip)) => {
+            port == bind.port()
+                && (ip == bind.ip() || (bind.ip().is_unspecified() && ip.is_loopback()))
+        }
+        _ => format!("{}:{}", host, port) == bind,
+    }
+}
+
+/// Finishes a `HEAD` response announcing a body of `length` bytes
+fn head_body(resp: &mut HttpResponseBuilder, length: u64) -> HttpResponse {
+    let body = stream::empty::<Result<Bytes, actix_web::Error>>();
+    resp.body(SizedStream::new(length, body))
+}
+
+/// Returns whether the directory entry is a cached data file, i.e. neither hidden nor a
+/// digest
+fn is_data_file(entry: &fs::DirEntry) -> bool {
+    let file_name = entry.file_name();
+    let file_name = file_name.to_string_lossy();
+
+    !file_name.starts_with('.')
+        && !file_name.ends_with(".digest")
+        && entry.file_type().map(|t| t.is_file()).unwrap_or(false)
+}
+
+/// Loads and verifies the digest of an existing file. Files that do not match their digest
+/// are removed together with it, files without a readable digest are skipped.
+fn load_existing(path: &Path, store: &DigestStore) -> io::Result<Option<Digest>> {
+    match Digest::for_path(path) {
+        Ok(digest) => verified(digest, store),
+        Err(err) => {
+            log::warn!("Skipping {}: {}", path.to_string_lossy(), err);
+            Ok(None)
+        }
+    }
+}
+
+/// Returns the digest if its file matches it, see `load_existing`
+fn verified(digest: Digest, store: &DigestStore) -> io::Result<Option<Digest>> {
+    let path = digest.get_file_path();
+
+    match digest.verify() {
+        Ok(()) => Ok(Some(digest)),
+        Err(DigestError::VerifyError) => {
+            log::error!("Removing corrupt file at {}", path.to_string_lossy());
+            store.remove(&digest)?;
+            Ok(None)
+        }
+        Err(err) => {
+            log::warn!("Skipping {}: {}", path.to_string_lossy(), err);
+            Ok(None)
+        }
+    }
+}
+
+/// Returns the digest if its file has the recorded size, a cheaper check than `verified`
+fn sized(digest: Digest, store: &DigestStore) -> io::Result<Option<Digest>> {
+    let path = digest.get_file_path();
+
+    match fs::metadata(&path) {
+        Ok(m) if m.len() == digest.size => Ok(Some(digest)),
+        Ok(m) => {
+            log::error!(
+                "Removing {} with {} instead of {} bytes",
+                path.to_string_lossy(),
+                m.len(),
+                digest.size
+            );
+            store.remove(&digest)?;
+            Ok(None)
+        }
+        Err(err) => {
+            log::warn!("Skipping {}: {}", path.to_string_lossy(), err);
+            Ok(None)
+        }
+    }
+}
+
+fn preprocess_existing<P: AsRef<Path>>(
+    root: P,
+    glob: &GlobSet,
+    store: &DigestStore,
+) -> io::Result<HashMap<String, Digest>> {
     let root = root.as_ref();
     let mut res = HashMap::new();
 
-    for entry in fs::read_dir(&root).unwrap() {
-        let path = entry.unwrap().path();
+    for entry in fs::read_dir(root)? {
+        let entry = entry?;
 
-        if glob.is_match(&path) {
-            let digest = Digest::for_path(&path).unwrap();
-            log::info!("Found existing file at {}", path.to_string_lossy());
-            digest.verify().unwrap();
+        if is_data_file(&entry) && glob.is_match(entry.file_name()) {
+            let path = entry.path();
+            if let Some(digest) = load_existing(&path, store)? {
+                log::info!("Found existing file at {}", path.to_string_lossy());
+                let file_name = digest.file_name.clone();
+                res.insert(file_name, digest);
+            }
+        }
+    }
+
+    Ok(res)
+}
+
+fn preprocess_variants<P: AsRef<Path>>(
+    root: P,
+    items: &HashMap<String, Digest>,
+    store: &DigestStore,
+) -> io::Result<HashMap<String, Vec<Digest>>> {
+    let mut res: HashMap<String, Vec<Digest>> = HashMap::new();
 
-            let file_name = digest.file_name.clone();
-            res.insert(file_name, digest);
+    for &encoding in Encoding::ALL.iter() {
+        let dir = root.as_ref().join(VARIANTS_DIR).join(encoding.as_str());
+        if !dir.is_dir() {
+            continue;
+        }
+
+        for entry in fs::read_dir(&dir)? {
+            let entry = entry?;
+            if !is_data_file(&entry) {
+                continue;
+            }
+
+            let path = entry.path();
+            match load_existing(&path, store)? {
+                Some(digest) if items.contains_key(&digest.file_name) => {
+                    log::info!("Found existing variant at {}", path.to_string_lossy());
+                    res.entry(digest.file_name.clone())
+                        .or_default()
+                        .push(digest);
+                }
+                Some(_) => log::warn!("Ignoring stale variant at {}", path.to_string_lossy()),
+                None => {}
+            }
         }
     }
 
-    res
+    Ok(res)
+}
+
+/// Cached files and their variants by file name
+type Existing = (HashMap<String, Digest>, HashMap<String, Vec<Digest>>);
+
+/// Loads the files and variants listed in the index of the entry at `root`. The records are
+/// trusted as long as the size of their file matches, the inspect API verifies the hashes.
+fn preprocess_index(root: &Path, glob: &GlobSet, store: &DigestStore) -> io::Result<Existing> {
+    let (originals, encoded): (Vec<Digest>, Vec<Digest>) = index::read(root)?
+        .into_iter()
+        .partition(|digest| digest.encoding.is_none());
+
+    let mut items = HashMap::new();
+    for digest in originals {
+        if glob.is_match(&digest.file_name) {
+            if let Some(digest) = sized(digest, store)? {
+                items.insert(digest.file_name.clone(), digest);
+            }
+        }
+    }
+
+    let mut variants: HashMap<String, Vec<Digest>> = HashMap::new();
+    for digest in encoded {
+        if items.contains_key(&digest.file_name) {
+            if let Some(digest) = sized(digest, store)? {
+                variants
+                    .entry(digest.file_name.clone())
+                    .or_default()
+                    .push(digest);
+            }
+        }
+    }
+
+    log::info!(
+        "Found {} existing files in the index of {}",
+        items.len(),
+        root.to_string_lossy()
+    );
+    Ok((items, variants))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_self() {
+        let url = |url| Url::parse(url).unwrap();
+
+        assert!(is_self(
+            &url("http://127.0.0.1:1337"),
+            None,
+            "127.0.0.1:1337"
+        ));
+        assert!(is_self(
+            &url("http://127.0.0.1:1337/"),
+            None,
+            "0.0.0.0:1337"
+        ));
+        assert!(!is_self(
+            &url("http://127.0.0.1:1338"),
+            None,
+            "0.0.0.0:1337"
+        ));
+        assert!(!is_self(&url("http://10.0.0.2:1337"), None, "0.0.0.0:1337"));
+        assert!(is_self(&url("http://localhost:1337"), None, "0.0.0.0:1337"));
+        assert!(is_self(&url("http://cache-1:1337"), None, "cache-1:1337"));
+        assert!(!is_self(&url("http://cache-2:1337"), None, "0.0.0.0:1337"));
+
+        let node_url = url("http://10.0.0.1:1337");
+        assert!(is_self(
+            &url("http://10.0.0.1:1337/"),
+            Some(&node_url),
+            "0.0.0.0:1337"
+        ));
+        assert!(!is_self(
+            &url("http://10.0.0.2:1337"),
+            Some(&node_url),
+            "0.0.0.0:1337"
+        ));
+    }
 }
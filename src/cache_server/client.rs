@@ -0,0 +1,76 @@
+use crate::config::{ConfigError, OriginAuth, OriginConfig};
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, AUTHORIZATION};
+use reqwest::{Certificate, Client, ClientBuilder, Identity, Proxy};
+use std::fs;
+use std::time::Duration;
+
+/// Builds the HTTP client for the requests to the origin of an entry
+pub fn build(config: &OriginConfig) -> Result<Client, ConfigError> {
+    let mut headers = HeaderMap::new();
+
+    for (name, value) in config.headers.iter() {
+        let name = HeaderName::from_bytes(name.as_bytes())
+            .map_err(|_| ConfigError::InvalidHeader(name.clone()))?;
+        let value = HeaderValue::from_str(value)
+            .map_err(|_| ConfigError::InvalidHeader(name.to_string()))?;
+        headers.insert(name, value);
+    }
+
+    if let Some(ref auth) = config.auth {
+        let value = match auth {
+            OriginAuth::Basic { username, password } => format!(
+                "Basic {}",
+                base64::encode(format!("{}:{}", username, password))
+            ),
+            OriginAuth::Bearer { token } => format!("Bearer {}", token),
+        };
+
+        let mut value = HeaderValue::from_str(&value)
+            .map_err(|_| ConfigError::InvalidHeader(AUTHORIZATION.to_string()))?;
+        value.set_sensitive(true);
+        headers.insert(AUTHORIZATION, value);
+    }
+
+    let mut builder = Client::builder()
+        .default_headers(headers)
+        .danger_accept_invalid_certs(!config.tls_verify);
+
+    if let Some(ref user_agent) = config.user_agent {
+        builder = builder.user_agent(user_agent.as_str());
+    }
+
+    builder = with_timeouts(builder, config);
+
+    if let Some(ref proxy) = config.proxy {
+        builder = builder.proxy(Proxy::all(proxy.as_str())?);
+    }
+
+    if let Some(ref path) = config.ca_bundle {
+        builder = builder.add_root_certificate(Certificate::from_pem(&fs::read(path)?)?);
+    }
+
+    if let Some(ref path) = config.client_cert {
+        let identity = Identity::from_pkcs12_der(&fs::read(path)?, &config.client_cert_password)?;
+        builder = builder.identity(identity);
+    }
+
+    Ok(builder.build()?)
+}
+
+/// Builds the HTTP client for the requests to the parents and peers of an entry, it only
+/// takes the timeouts of the origin, its headers, credentials and proxy stay with the origin
+pub fn build_node(config: &OriginConfig) -> Result<Client, ConfigError> {
+    Ok(with_timeouts(Client::builder(), config).build()?)
+}
+
+fn with_timeouts(mut builder: ClientBuilder, config: &OriginConfig) -> ClientBuilder {
+    if let Some(timeout) = config.connect_timeout {
+        builder = builder.connect_timeout(Duration::from_secs(timeout));
+    }
+
+    if let Some(timeout) = config.timeout {
+        builder = builder.timeout(Duration::from_secs(timeout));
+    }
+
+    builder
+}
@@ -0,0 +1,214 @@
+use super::index::DigestStore;
+use crate::config::{CompressionConfig, Encoding};
+use crate::digest::{Digest, DigestError};
+use blake3::{Hash, Hasher};
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+/// Directory below the entry root in which the compressed variants are stored, one
+/// subdirectory per encoding.
+pub const VARIANTS_DIR: &str = ".variants";
+/// Suffix of the file a variant is compressed to, after a leading `.`
+pub const COMPRESS_SUFFIX: &str = ".compress";
+
+/// Compiled form of an entry's `CompressionConfig`.
+pub struct CompressionRules {
+    encodings: Vec<Encoding>,
+    on_the_fly: bool,
+    skip_types: GlobSet,
+    min_size: u64,
+}
+
+impl CompressionRules {
+    pub fn new(config: &CompressionConfig) -> Result<Self, globset::Error> {
+        let mut builder = GlobSetBuilder::new();
+        for pattern in config.skip_types.iter() {
+            builder.add(Glob::new(pattern)?);
+        }
+
+        Ok(CompressionRules {
+            encodings: config.encodings.clone(),
+            on_the_fly: config.on_the_fly,
+            skip_types: builder.build()?,
+            min_size: config.min_size,
+        })
+    }
+
+    pub fn encodings(&self) -> &[Encoding] {
+        &self.encodings
+    }
+
+    pub fn on_the_fly(&self) -> bool {
+        self.on_the_fly
+    }
+
+    /// Whether the file described by `digest` is served in different encodings
+    pub fn applies_to(&self, digest: &Digest) -> bool {
+        let mime = digest.content_type.split(';').next().unwrap_or("").trim();
+
+        (self.on_the_fly || !self.encodings.is_empty())
+            && digest.size >= self.min_size
+            && !self.skip_types.is_match(mime.to_ascii_lowercase())
+    }
+}
+
+/// Picks the best of the `available` encodings for the given `Accept-Encoding` header.
+///
+/// Encodings are weighted by their quality value, ties are broken by the order of
+/// `available`. Returns `None` if the client accepts none of them.
+pub fn negotiate(accept_encoding: &str, available: &[Encoding]) -> Option<Encoding> {
+    let mut wildcard = None;
+    let mut accepted = Vec::new();
+
+    for item in accept_encoding.split(',') {
+        let mut parts = item.split(';');
+        let coding = parts.next().unwrap_or("").trim().to_ascii_lowercase();
+
+        let quality = parts
+            .filter_map(|p| {
+                let p = p.trim();
+                if p.starts_with("q=") || p.starts_with("Q=") {
+                    p[2..].trim().parse::<f32>().ok()
+                } else {
+                    None
+                }
+            })
+            .next()
+            .unwrap_or(1.0);
+
+        match coding.as_str() {
+            "*" => wildcard = Some(quality),
+            "gzip" | "x-gzip" => accepted.push((Encoding::Gzip, quality)),
+            "br" => accepted.push((Encoding::Brotli, quality)),
+            "zstd" => accepted.push((Encoding::Zstd, quality)),
+            _ => {}
+        }
+    }
+
+    let mut best: Option<(Encoding, f32)> = None;
+
+    for &encoding in available {
+        let quality = accepted
+            .iter()
+            .find(|(e, _)| *e == encoding)
+            .map(|(_, q)| *q)
+            .or(wildcard)
+            .unwrap_or(0.0);
+
+        if quality > 0.0 && best.is_none_or(|(_, q)| quality > q) {
+            best = Some((encoding, quality));
+        }
+    }
+
+    best.map(|(e, _)| e)
+}
+
+pub fn variant_path(root: &Path, encoding: Encoding, file_name: &str) -> PathBuf {
+    root.join(VARIANTS_DIR)
+        .join(encoding.as_str())
+        .join(file_name)
+}
+
+/// Compresses the file described by `digest` and stores the result with its own digest
+/// below `root`.
+///
+/// Returns `None` if the compressed file is not smaller than the original.
+pub fn create_variant(
+    digest: &Digest,
+    encoding: Encoding,
+    root: &Path,
+    store: &DigestStore,
+) -> Result<Option<Digest>, DigestError> {
+    let path = variant_path(root, encoding, &digest.file_name);
+    let dir = path.parent().ok_or(DigestError::FileNotFound)?;
+    fs::create_dir_all(dir)?;
+
+    let tmp_path = dir.join(format!(".{}{}", digest.file_name, COMPRESS_SUFFIX));
+    let hash = compress(encoding, &digest.get_file_path(), &tmp_path)?;
+
+    if fs::metadata(&tmp_path)?.len() >= digest.size {
+        fs::remove_file(&tmp_path)?;
+        return Ok(None);
+    }
+
+    let mut variant =
+        Digest::new(&tmp_path, &digest.content_type, Vec::new(), hash).with_encoding(encoding);
+    store.commit(&mut variant, &path)?;
+
+    Ok(Some(variant))
+}
+
+fn compress(encoding: Encoding, source: &Path, target: &Path) -> io::Result<Hash> {
+    let mut input = io::BufReader::new(fs::File::open(source)?);
+    let mut output = HashingWriter {
+        inner: io::BufWriter::new(fs::File::create(target)?),
+        hasher: Hasher::new(),
+    };
+
+    match encoding {
+        Encoding::Gzip => {
+            let mut encoder =
+                flate2::write::GzEncoder::new(&mut output, flate2::Compression::best());
+            io::copy(&mut input, &mut encoder)?;
+            encoder.finish()?;
+        }
+        Encoding::Brotli => {
+            let mut encoder = brotli::CompressorWriter::new(&mut output, 4096, 9, 22);
+            io::copy(&mut input, &mut encoder)?;
+            encoder.into_inner();
+        }
+        Encoding::Zstd => {
+            zstd::stream::copy_encode(&mut input, &mut output, 15)?;
+        }
+    }
+
+    output.inner.flush()?;
+    Ok(output.hasher.finalize())
+}
+
+struct HashingWriter<W: Write> {
+    inner: W,
+    hasher: Hasher,
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.hasher.update(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_negotiate() {
+        use Encoding::*;
+
+        let all = [Brotli, Zstd, Gzip];
+
+        let tests = vec![
+            ("", &all[..], None),
+            ("identity", &all[..], None),
+            ("gzip", &all[..], Some(Gzip)),
+            ("gzip, deflate, br", &all[..], Some(Brotli)),
+            ("gzip;q=1.0, br;q=0.5", &all[..], Some(Gzip)),
+            ("br;q=0, *", &all[..], Some(Zstd)),
+            ("*;q=0.1, gzip;q=0", &[Gzip][..], None),
+            ("x-gzip", &[Gzip][..], Some(Gzip)),
+            ("br", &[Gzip][..], None),
+        ];
+
+        for (header, available, expected) in tests {
+            assert_eq!(negotiate(header, available), expected, "{}", header);
+        }
+    }
+}
@@ -1,38 +1,93 @@
-use crate::digest::Digest;
+use super::headers::HeaderRules;
+use super::index::DigestStore;
+use super::shutdown::Deadline;
+use crate::digest::{Digest, DigestError};
+use crate::util::hash_serde;
+use crate::util::throttle::Throttle;
+use actix_rt::time::delay_for;
 use blake3::Hasher;
+use futures_util::future::{self, Either};
 use futures_util::StreamExt;
-use reqwest::Client;
+use prometheus::IntCounter;
+use reqwest::header::{self, HeaderName};
+use reqwest::{Client, RequestBuilder, Response, StatusCode};
 use std::fs;
-use std::io::Write;
+use std::io::{self, Write};
 use std::path::{Path, PathBuf};
+use std::time::Duration;
 use thiserror::Error;
 use url::Url;
 
-enum DownloadStatus {
-    NotStarted,
-    Received(usize),
-    Finished,
-}
+/// Suffix of the file a download is written to, after a leading `.`
+pub const DOWNLOAD_SUFFIX: &str = ".download";
 
 pub struct Downloader<'a> {
     client: &'a Client,
+    headers: &'a HeaderRules,
+    store: &'a DigestStore,
     url: Url,
     path: PathBuf,
-    started: bool,
+    bytes_counter: Option<IntCounter>,
+    verify_etag: bool,
+    request_headers: Vec<(HeaderName, &'static str)>,
+    deadline: Option<Deadline>,
+    throttle: Throttle,
 }
 
 impl<'a> Downloader<'a> {
-    pub fn new<P: AsRef<Path>>(client: &'a Client, url: Url, path: P) -> Self {
+    pub fn new<P: AsRef<Path>>(
+        client: &'a Client,
+        headers: &'a HeaderRules,
+        store: &'a DigestStore,
+        url: Url,
+        path: P,
+    ) -> Self {
         Self {
             client,
+            headers,
+            store,
             url,
             path: path.as_ref().to_owned(),
-            started: false,
+            bytes_counter: None,
+            verify_etag: false,
+            request_headers: Vec::new(),
+            deadline: None,
+            throttle: Throttle::default(),
         }
     }
 
-    pub async fn download(&self) -> Result<Digest, DownloadError>
-    {
+    /// Count the downloaded bytes in the given counter
+    pub fn with_bytes_counter(mut self, counter: IntCounter) -> Self {
+        self.bytes_counter = Some(counter);
+        self
+    }
+
+    /// Check the download against the blake3 hash in the `ETag` of the response, as sent by
+    /// other cache nodes
+    pub fn with_etag_verification(mut self) -> Self {
+        self.verify_etag = true;
+        self
+    }
+
+    /// Send an additional header with the request
+    pub fn with_request_header(mut self, name: HeaderName, value: &'static str) -> Self {
+        self.request_headers.push((name, value));
+        self
+    }
+
+    /// Limit the download rate
+    pub fn with_throttle(mut self, throttle: Throttle) -> Self {
+        self.throttle = throttle;
+        self
+    }
+
+    /// Stop at `deadline`, saving the progress so the download can be resumed later
+    pub fn with_deadline(mut self, deadline: Deadline) -> Self {
+        self.deadline = Some(deadline);
+        self
+    }
+
+    pub async fn download(&self) -> Result<Digest, DownloadError> {
         let path = &self.path;
         let file_name = if let Some(file_name) = path.file_name() {
             file_name.to_string_lossy()
@@ -40,41 +95,278 @@ impl<'a> Downloader<'a> {
             return Err(DownloadError::PathError);
         };
 
-        let resp = self.client.get(self.url.clone()).send().await?.error_for_status()?;
+        let download_fn = format!(".{}{}", file_name, DOWNLOAD_SUFFIX);
+        let download_path = path.with_file_name(download_fn);
+        fs::create_dir_all(path.parent().unwrap())?;
+
+        let mut hasher = Hasher::new();
+        let mut partial = resume_point(&download_path, &mut hasher);
+
+        let mut resp = check_status(self.request(partial.as_ref()).send().await?)?;
+
+        // Only the rest of the partial download is expected, any other range would end up
+        // in the wrong place
+        if resp.status() == StatusCode::PARTIAL_CONTENT {
+            let start = resp
+                .headers()
+                .get(header::CONTENT_RANGE)
+                .and_then(|v| v.to_str().ok())
+                .and_then(content_range)
+                .map(|(start, _)| start);
+
+            if start.is_none() || start != partial.as_ref().map(|p| p.downloaded) {
+                let partial = partial.take().ok_or(DownloadError::UnexpectedRange)?;
+                log::warn!("Unexpected range from {}, downloading all of it", self.url);
+                partial.remove()?;
+                hasher.reset();
+
+                resp = check_status(self.request(None).send().await?)?;
+                if resp.status() == StatusCode::PARTIAL_CONTENT {
+                    return Err(DownloadError::UnexpectedRange);
+                }
+            }
+        }
 
         let headers = resp.headers();
-        let content_type: String = if let Some(value) = headers.get(reqwest::header::CONTENT_TYPE) {
+        let content_type: String = if let Some(value) = headers.get(header::CONTENT_TYPE) {
             value.to_str().unwrap().to_owned()
         } else {
             "unknown".to_owned()
         };
+        let passthrough = self.headers.collect(headers);
+        let header_str = |name| {
+            headers
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .map(|v| v.to_owned())
+        };
+        let etag = header_str(header::ETAG);
+        let expected = if self.verify_etag {
+            let hash = etag
+                .as_deref()
+                .map(|etag| etag.trim_start_matches("W/").trim_matches('"'))
+                .and_then(hash_serde::from_hex)
+                .ok_or(DownloadError::HashMismatch)?;
+            Some(hash)
+        } else {
+            None
+        };
+        let last_modified = header_str(header::LAST_MODIFIED);
 
-        let download_fn = format!(".{}.download", file_name);
-        let download_path = path.with_file_name(download_fn);
+        // The origin answers the range request with the rest of the file only if the file
+        // did not change, otherwise it sends all of it
+        let resumed = header_str(header::CONTENT_RANGE)
+            .and_then(|v| content_range(&v))
+            .filter(|_| resp.status() == StatusCode::PARTIAL_CONTENT);
 
-        log::debug!("Downloading {} to {}", self.url, download_path.to_string_lossy());
+        let mut output = match resumed {
+            Some((start, _)) => {
+                log::debug!(
+                    "Resuming {} at {} bytes into {}",
+                    self.url,
+                    start,
+                    download_path.to_string_lossy()
+                );
+                fs::OpenOptions::new().append(true).open(&download_path)?
+            }
+            None => {
+                if let Some(ref partial) = partial {
+                    partial.remove()?;
+                    hasher.reset();
+                }
+                log::debug!(
+                    "Downloading {} to {}",
+                    self.url,
+                    download_path.to_string_lossy()
+                );
+                fs::File::create(&download_path)?
+            }
+        };
 
-        let mut hasher = Hasher::new();
-        fs::create_dir_all(path.parent().unwrap())?;
-        let mut output = fs::File::create(&download_path)?;
+        let progress = Progress {
+            path: &download_path,
+            content_type: &content_type,
+            headers: &passthrough,
+            etag: etag.as_deref(),
+            last_modified: last_modified.as_deref(),
+            size: resumed.and_then(|(_, total)| total).or_else(|| {
+                resp.content_length()
+                    .filter(|_| resp.status() == StatusCode::OK)
+            }),
+        };
 
         let mut stream = resp.bytes_stream();
+        let mut deadline = self.deadline.clone();
+
+        let mut pause = Duration::from_secs(0);
+
+        loop {
+            // Waiting for the throttle before reading holds back the origin as well
+            let next = Box::pin(async {
+                if pause > Duration::from_secs(0) {
+                    delay_for(pause).await;
+                }
+                stream.next().await
+            });
 
-        while let Some(item) = stream.next().await {
-            let item = item?;
+            let item = match deadline {
+                Some(ref mut deadline) => match future::select(next, deadline).await {
+                    Either::Left((item, _)) => item,
+                    Either::Right(_) => {
+                        progress.checkpoint(&output, &hasher)?;
+                        return Err(DownloadError::ShuttingDown);
+                    }
+                },
+                None => next.await,
+            };
+
+            let item = match item {
+                Some(Ok(item)) => item,
+                Some(Err(err)) => {
+                    progress.checkpoint(&output, &hasher)?;
+                    return Err(err.into());
+                }
+                None => break,
+            };
+
+            if let Some(ref bytes_counter) = self.bytes_counter {
+                bytes_counter.inc_by(item.len() as u64);
+            }
             hasher.write_all(&item).unwrap();
             output.write_all(&item)?;
+            pause = self.throttle.take(item.len() as u64);
 
             // TODO Return this as a stream as well s.t. we can pass it through
         }
 
         let hash = hasher.finalize();
 
-        fs::rename(download_path, path)?;
+        if expected.is_some_and(|expected| expected != hash) {
+            fs::remove_file(download_path)?;
+            return Err(DownloadError::HashMismatch);
+        }
+
+        // The `ETag` of another cache node is its hash and not the one of the origin
+        let etag = if self.verify_etag { None } else { etag };
+        let mut res = Digest::new(&download_path, &content_type, passthrough, hash)
+            .with_validators(etag, last_modified);
+        self.store.commit(&mut res, path)?;
+
+        if let Some(partial) = partial {
+            // Only the partial digest is left
+            partial.remove()?;
+        }
 
-        let res = Digest::new(path, &content_type, hash);
         Ok(res)
     }
+
+    /// Prepares the request, for the rest of the file if a `partial` download is resumed
+    fn request(&self, partial: Option<&Digest>) -> RequestBuilder {
+        let mut req = self.client.get(self.url.clone());
+        if self.verify_etag {
+            // The hash in the `ETag` is the one of the uncompressed file
+            req = req.header(header::ACCEPT_ENCODING, "identity");
+        }
+        for (name, value) in self.request_headers.iter() {
+            req = req.header(name, *value);
+        }
+        if let Some(partial) = partial {
+            req = req.header(header::RANGE, format!("bytes={}-", partial.downloaded));
+            if let Some(validator) = partial
+                .origin_etag
+                .as_ref()
+                .or(partial.last_modified.as_ref())
+            {
+                req = req.header(header::IF_RANGE, validator.as_str());
+            }
+        }
+        req
+    }
+}
+
+/// What is known about a running download, to save it in a partial digest
+struct Progress<'a> {
+    path: &'a Path,
+    content_type: &'a str,
+    headers: &'a [(String, String)],
+    etag: Option<&'a str>,
+    last_modified: Option<&'a str>,
+    size: Option<u64>,
+}
+
+impl Progress<'_> {
+    /// Saves the progress of an interrupted download into a partial digest next to the
+    /// download file. Resuming needs the full size and a validator of the origin, without
+    /// them the download file is dropped.
+    fn checkpoint(&self, output: &fs::File, hasher: &Hasher) -> io::Result<()> {
+        let size = match self.size {
+            Some(size) if self.etag.is_some() || self.last_modified.is_some() => size,
+            _ => return fs::remove_file(self.path),
+        };
+
+        output.sync_data()?;
+
+        let mut digest = Digest::new(
+            self.path,
+            self.content_type,
+            self.headers.to_vec(),
+            hasher.finalize(),
+        )
+        .with_validators(self.etag.map(str::to_owned), None)
+        .with_size(size);
+        digest.last_modified = self.last_modified.map(str::to_owned);
+        digest.write().map_err(io::Error::other)?;
+
+        log::info!(
+            "Saved {} of {} bytes of {} to resume later",
+            digest.downloaded,
+            size,
+            self.path.to_string_lossy()
+        );
+        Ok(())
+    }
+}
+
+/// Returns the partial digest of a download to resume, feeding the part already on disk into
+/// `hasher`. Partial files not matching their digest are dropped.
+fn resume_point(download_path: &Path, hasher: &mut Hasher) -> Option<Digest> {
+    let partial = Digest::for_path(download_path).ok()?;
+
+    let intact = partial.is_partial()
+        && fs::File::open(download_path)
+            .and_then(|mut file| io::copy(&mut file, hasher))
+            .is_ok_and(|len| len == partial.downloaded)
+        && hasher.finalize() == partial.hash();
+
+    if intact {
+        Some(partial)
+    } else {
+        log::warn!(
+            "Dropping partial download {}",
+            download_path.to_string_lossy()
+        );
+        hasher.reset();
+        let _ = partial.remove();
+        None
+    }
+}
+
+/// Parses the start and the full length, if known, from a `Content-Range` header
+fn content_range(value: &str) -> Option<(u64, Option<u64>)> {
+    let (range, total) = value.strip_prefix("bytes ")?.split_once('/')?;
+    let start = range.split_once('-')?.0.trim().parse().ok()?;
+    Some((start, total.trim().parse().ok()))
+}
+
+/// Turns an error status of the origin into a `DownloadError::OriginStatus`
+pub fn check_status(resp: Response) -> Result<Response, DownloadError> {
+    let status = resp.status();
+
+    if status.is_client_error() || status.is_server_error() {
+        Err(DownloadError::OriginStatus(status))
+    } else {
+        Ok(resp)
+    }
 }
 
 #[derive(Error, Debug)]
@@ -85,6 +377,209 @@ pub enum DownloadError {
     #[error("HTTP error")]
     RequestError(#[from] reqwest::Error),
 
+    #[error("Failed to store the digest")]
+    DigestError(#[from] DigestError),
+
     #[error("Path error")]
     PathError,
+
+    #[error("Hash mismatch")]
+    HashMismatch,
+
+    #[error("Origin responded with an unexpected range")]
+    UnexpectedRange,
+
+    #[error("Origin responded with {0}")]
+    OriginStatus(StatusCode),
+
+    #[error("Shutting down")]
+    ShuttingDown,
+}
+
+impl DownloadError {
+    /// Status of the origin response that caused the error
+    pub fn origin_status(&self) -> Option<StatusCode> {
+        match self {
+            DownloadError::OriginStatus(status) => Some(*status),
+            DownloadError::RequestError(err) => err.status(),
+            _ => None,
+        }
+    }
+
+    /// Status to answer the client with and the reason sent in `X-Cache-Error`.
+    ///
+    /// Missing files keep the status of the origin, other origin failures become
+    /// `502 Bad Gateway` or `504 Gateway Timeout`. A `403 Forbidden` is only passed on if
+    /// `forward_forbidden` is set.
+    pub fn client_status(&self, forward_forbidden: bool) -> (StatusCode, &'static str) {
+        match self {
+            DownloadError::IoError(err) if err.kind() == io::ErrorKind::StorageFull => {
+                (StatusCode::INSUFFICIENT_STORAGE, "storage_full")
+            }
+            DownloadError::DigestError(DigestError::IoError(err))
+                if err.kind() == io::ErrorKind::StorageFull =>
+            {
+                (StatusCode::INSUFFICIENT_STORAGE, "storage_full")
+            }
+            DownloadError::IoError(_) | DownloadError::DigestError(_) => {
+                (StatusCode::INTERNAL_SERVER_ERROR, "io_error")
+            }
+            DownloadError::PathError => (StatusCode::NOT_FOUND, "invalid_path"),
+            DownloadError::ShuttingDown => (StatusCode::SERVICE_UNAVAILABLE, "shutting_down"),
+            DownloadError::HashMismatch => (StatusCode::BAD_GATEWAY, "hash_mismatch"),
+            DownloadError::UnexpectedRange => (StatusCode::BAD_GATEWAY, "origin_error"),
+            DownloadError::RequestError(err) if err.is_timeout() => {
+                (StatusCode::GATEWAY_TIMEOUT, "origin_timeout")
+            }
+            _ => match self.origin_status() {
+                Some(StatusCode::NOT_FOUND) => (StatusCode::NOT_FOUND, "origin_not_found"),
+                Some(StatusCode::GONE) => (StatusCode::GONE, "origin_gone"),
+                Some(StatusCode::FORBIDDEN) if forward_forbidden => {
+                    (StatusCode::FORBIDDEN, "origin_forbidden")
+                }
+                Some(StatusCode::FORBIDDEN) => (StatusCode::BAD_GATEWAY, "origin_forbidden"),
+                Some(StatusCode::GATEWAY_TIMEOUT) => {
+                    (StatusCode::GATEWAY_TIMEOUT, "origin_timeout")
+                }
+                Some(_) => (StatusCode::BAD_GATEWAY, "origin_error"),
+                None => (StatusCode::BAD_GATEWAY, "origin_unreachable"),
+            },
+        }
+    }
+
+    /// Whether the error happened on this node rather than at the origin
+    pub fn is_local(&self) -> bool {
+        !matches!(
+            self,
+            DownloadError::RequestError(_)
+                | DownloadError::HashMismatch
+                | DownloadError::UnexpectedRange
+                | DownloadError::OriginStatus(_)
+        )
+    }
+
+    /// Whether the origin failed to answer, as opposed to e.g. not having the file
+    pub fn is_origin_failure(&self) -> bool {
+        match self {
+            DownloadError::RequestError(err) => err.status().is_none_or(|s| s.is_server_error()),
+            DownloadError::HashMismatch | DownloadError::UnexpectedRange => true,
+            DownloadError::OriginStatus(status) => status.is_server_error(),
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::HeaderConfig;
+    use crate::util::test_dir::TestDir;
+    use crate::util::test_server;
+
+    #[test]
+    fn test_content_range() {
+        assert_eq!(content_range("bytes 5-9/10"), Some((5, Some(10))));
+        assert_eq!(content_range("bytes 5-9/*"), Some((5, None)));
+        assert_eq!(content_range("bytes */10"), None);
+        assert_eq!(content_range("5-9/10"), None);
+    }
+
+    #[test]
+    fn test_fill_from_node() {
+        let port = test_server::respond_once(
+            b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\nX-Cache: HIT\r\nAge: 60\r\n\
+              X-Cache-Node: parent\r\nX-Custom: 1\r\nConnection: close\r\n\r\nhello",
+        );
+        let dir = TestDir::new("fill-node");
+        let headers = HeaderRules::new(
+            &HeaderConfig {
+                passthrough: vec!["x-*".to_owned(), "age".to_owned()],
+                ..HeaderConfig::default()
+            },
+            "X-Cache-Node",
+        )
+        .unwrap();
+        let url = Url::parse(&format!("http://127.0.0.1:{}/a", port)).unwrap();
+
+        let digest = actix_rt::System::new("test").block_on(async move {
+            let client = Client::new();
+            Downloader::new(&client, &headers, &DigestStore::Sidecar, url, dir.join("a"))
+                .download()
+                .await
+                .unwrap()
+        });
+
+        assert_eq!(
+            digest.headers,
+            vec![("x-custom".to_owned(), "1".to_owned())]
+        );
+    }
+
+    #[test]
+    fn test_resume() {
+        let dir = TestDir::new("download");
+        let path = dir.join(".a.download");
+        fs::write(&path, "hello").unwrap();
+        let output = fs::File::open(&path).unwrap();
+        let mut hasher = Hasher::new();
+        hasher.update(b"hello");
+
+        let progress = |etag, size| Progress {
+            path: &path,
+            content_type: "text/plain",
+            headers: &[],
+            etag,
+            last_modified: None,
+            size,
+        };
+
+        // Without a validator or the full size the download cannot be resumed
+        progress(None, Some(10))
+            .checkpoint(&output, &hasher)
+            .unwrap();
+        assert!(!path.exists());
+
+        fs::write(&path, "hello").unwrap();
+        progress(Some("\"x\""), None)
+            .checkpoint(&output, &hasher)
+            .unwrap();
+        assert!(!path.exists());
+
+        fs::write(&path, "hello").unwrap();
+        progress(Some("\"x\""), Some(10))
+            .checkpoint(&output, &hasher)
+            .unwrap();
+
+        let mut hasher = Hasher::new();
+        let partial = resume_point(&path, &mut hasher).unwrap();
+        assert_eq!((partial.downloaded, partial.size), (5, 10));
+        assert_eq!(partial.origin_etag.as_deref(), Some("\"x\""));
+        assert_eq!(hasher.finalize(), blake3::hash(b"hello"));
+
+        // A partial file that does not match its digest is dropped
+        fs::write(&path, "jello").unwrap();
+        let mut hasher = Hasher::new();
+        assert!(resume_point(&path, &mut hasher).is_none());
+        assert!(!path.exists());
+        assert!(Digest::for_path(&path).is_err());
+        assert_eq!(hasher.finalize(), Hasher::new().finalize());
+    }
+
+    #[test]
+    fn test_client_status() {
+        let status = |err: DownloadError, forward| err.client_status(forward).0;
+        let origin = DownloadError::OriginStatus;
+
+        assert_eq!(status(origin(StatusCode::NOT_FOUND), false), 404);
+        assert_eq!(status(origin(StatusCode::GONE), false), 410);
+        assert_eq!(status(origin(StatusCode::FORBIDDEN), false), 502);
+        assert_eq!(status(origin(StatusCode::FORBIDDEN), true), 403);
+        assert_eq!(status(origin(StatusCode::SERVICE_UNAVAILABLE), false), 502);
+        assert_eq!(status(origin(StatusCode::GATEWAY_TIMEOUT), false), 504);
+
+        let full = io::Error::from(io::ErrorKind::StorageFull);
+        assert_eq!(status(full.into(), false), 507);
+        let denied = io::Error::from(io::ErrorKind::PermissionDenied);
+        assert_eq!(status(denied.into(), false), 500);
+    }
 }
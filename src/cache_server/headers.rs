@@ -0,0 +1,210 @@
+use crate::config::{ConfigError, HeaderConfig};
+use actix_web::http::header::{HeaderName, HeaderValue};
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+use thiserror::Error;
+
+/// Headers that are generated by the cache node itself and are thus never taken over from
+/// the origin response, nor from the response of a parent or peer node. The node id header
+/// is configured and reserved as well.
+static RESERVED: &[&str] = &[
+    "accept-ranges",
+    "age",
+    "connection",
+    "content-encoding",
+    "content-length",
+    "content-range",
+    "content-type",
+    "etag",
+    "keep-alive",
+    "last-modified",
+    "transfer-encoding",
+    "vary",
+    "x-cache",
+    "x-cache-error",
+];
+
+/// Compiled form of an entry's `HeaderConfig`.
+pub struct HeaderRules {
+    passthrough: GlobSet,
+    strip: GlobSet,
+    add: Vec<(String, String)>,
+    node_id_header: String,
+}
+
+impl HeaderRules {
+    pub fn new(config: &HeaderConfig, node_id_header: &str) -> Result<Self, HeaderRulesError> {
+        let mut rules = HeaderRules {
+            passthrough: header_globset(&config.passthrough)?,
+            strip: header_globset(&config.strip)?,
+            add: Vec::new(),
+            node_id_header: node_id_header.to_ascii_lowercase(),
+        };
+
+        for (name, value) in config.add.iter() {
+            let name = HeaderName::from_bytes(name.as_bytes())
+                .map_err(|_| HeaderRulesError::InvalidHeader(name.clone()))?;
+            HeaderValue::from_str(value)
+                .map_err(|_| HeaderRulesError::InvalidHeader(name.to_string()))?;
+            if rules.is_reserved(name.as_str()) {
+                return Err(HeaderRulesError::ReservedHeader(name.to_string()));
+            }
+            rules.add.push((name.as_str().to_owned(), value.clone()));
+        }
+
+        Ok(rules)
+    }
+
+    /// Whether the cache node sets the header itself, `name` is expected in lowercase
+    fn is_reserved(&self, name: &str) -> bool {
+        RESERVED.contains(&name) || name == self.node_id_header
+    }
+
+    /// Select the origin response headers that are to be persisted in the digest.
+    pub fn collect(&self, headers: &reqwest::header::HeaderMap) -> Vec<(String, String)> {
+        headers
+            .iter()
+            .filter(|(name, _)| {
+                !self.is_reserved(name.as_str()) && self.passthrough.is_match(name.as_str())
+            })
+            .filter_map(|(name, value)| {
+                let value = value.to_str().ok()?;
+                Some((name.as_str().to_owned(), value.to_owned()))
+            })
+            .collect()
+    }
+
+    /// Apply the strip and add rules to the headers stored in a digest. Reserved headers
+    /// stored by earlier versions are dropped as well.
+    pub fn apply(&self, stored: &[(String, String)]) -> Vec<(String, String)> {
+        let mut res: Vec<(String, String)> = stored
+            .iter()
+            .filter(|(name, _)| {
+                !self.is_reserved(name)
+                    && !self.strip.is_match(name)
+                    && !self.add.iter().any(|(n, _)| n == name)
+            })
+            .cloned()
+            .collect();
+
+        res.extend(self.add.iter().cloned());
+        res
+    }
+}
+
+fn header_globset(patterns: &[String]) -> Result<GlobSet, globset::Error> {
+    let mut builder = GlobSetBuilder::new();
+
+    for pattern in patterns {
+        builder.add(GlobBuilder::new(pattern).case_insensitive(true).build()?);
+    }
+
+    builder.build()
+}
+
+#[derive(Error, Debug)]
+pub enum HeaderRulesError {
+    #[error(transparent)]
+    GlobError(#[from] globset::Error),
+
+    #[error("Invalid header {0}")]
+    InvalidHeader(String),
+
+    #[error("Header {0} is set by the cache node")]
+    ReservedHeader(String),
+}
+
+impl From<HeaderRulesError> for ConfigError {
+    fn from(err: HeaderRulesError) -> Self {
+        match err {
+            HeaderRulesError::GlobError(err) => ConfigError::GlobError(err),
+            HeaderRulesError::InvalidHeader(name) => ConfigError::InvalidHeader(name),
+            HeaderRulesError::ReservedHeader(name) => ConfigError::ReservedHeader(name),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::header::HeaderMap;
+
+    fn rules(passthrough: &[&str], strip: &[&str], add: &[(&str, &str)]) -> HeaderRules {
+        let config = HeaderConfig {
+            passthrough: passthrough.iter().map(|s| s.to_string()).collect(),
+            strip: strip.iter().map(|s| s.to_string()).collect(),
+            add: add
+                .iter()
+                .map(|(n, v)| (n.to_string(), v.to_string()))
+                .collect(),
+        };
+        HeaderRules::new(&config, "X-Cache-Node").unwrap()
+    }
+
+    #[test]
+    fn test_collect() {
+        let rules = rules(&["Content-Language", "x-*", "content-length"], &[], &[]);
+
+        let mut headers = HeaderMap::new();
+        headers.insert("content-language", "de".parse().unwrap());
+        headers.insert("content-length", "10".parse().unwrap());
+        headers.insert("x-custom", "1".parse().unwrap());
+        headers.insert("server", "nginx".parse().unwrap());
+
+        let mut got = rules.collect(&headers);
+        got.sort();
+
+        assert_eq!(
+            got,
+            vec![
+                ("content-language".to_owned(), "de".to_owned()),
+                ("x-custom".to_owned(), "1".to_owned()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_reserved() {
+        let rules = rules(&["x-*", "age"], &[], &[]);
+
+        let mut headers = HeaderMap::new();
+        headers.insert("x-cache", "HIT".parse().unwrap());
+        headers.insert("x-cache-node", "parent".parse().unwrap());
+        headers.insert("age", "60".parse().unwrap());
+        headers.insert("x-custom", "1".parse().unwrap());
+
+        assert_eq!(
+            rules.collect(&headers),
+            vec![("x-custom".to_owned(), "1".to_owned())]
+        );
+
+        let config = HeaderConfig {
+            add: vec![("X-Cache".to_owned(), "HIT".to_owned())]
+                .into_iter()
+                .collect(),
+            ..HeaderConfig::default()
+        };
+        assert!(matches!(
+            HeaderRules::new(&config, "X-Cache-Node"),
+            Err(HeaderRulesError::ReservedHeader(_))
+        ));
+    }
+
+    #[test]
+    fn test_apply() {
+        let rules = rules(&[], &["X-Internal-*"], &[("Cache-Control", "max-age=60")]);
+
+        let stored = vec![
+            ("cache-control".to_owned(), "no-cache".to_owned()),
+            ("x-internal-id".to_owned(), "42".to_owned()),
+            ("link".to_owned(), "<a>".to_owned()),
+        ];
+
+        assert_eq!(
+            rules.apply(&stored),
+            vec![
+                ("link".to_owned(), "<a>".to_owned()),
+                ("cache-control".to_owned(), "max-age=60".to_owned()),
+            ]
+        );
+    }
+}
@@ -0,0 +1,392 @@
+use super::compression::VARIANTS_DIR;
+use crate::config::{CacheConfig, Config, Encoding};
+use crate::digest::{self, Digest, DigestError};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// File name of the index in the directory of an entry
+const INDEX_FILE: &str = ".index";
+
+/// Where the digests of the cached files of an entry are kept
+pub enum DigestStore {
+    /// A `.digest` file next to every cached file
+    Sidecar,
+    Index(Index),
+}
+
+impl DigestStore {
+    pub fn open(root: &Path, config: &CacheConfig) -> io::Result<Self> {
+        if config.index {
+            Ok(DigestStore::Index(Index::open(root)?))
+        } else {
+            Ok(DigestStore::Sidecar)
+        }
+    }
+
+    pub fn is_index(&self) -> bool {
+        matches!(self, DigestStore::Index(_))
+    }
+
+    /// Moves the staged file of `digest` to `path` and stores the digest. The index record is
+    /// appended first, records of files that never got renamed are dropped on compaction.
+    pub fn commit(&self, digest: &mut Digest, path: &Path) -> Result<(), DigestError> {
+        match self {
+            DigestStore::Sidecar => digest.commit(path),
+            DigestStore::Index(index) => digest.move_to(path, |digest| index.put(digest)),
+        }
+    }
+
+    /// Deletes the file and its digest
+    pub fn remove(&self, digest: &Digest) -> io::Result<()> {
+        digest.remove()?;
+        match self {
+            DigestStore::Sidecar => Ok(()),
+            DigestStore::Index(index) => index.remove(digest),
+        }
+    }
+}
+
+/// Append-only log of the digests of an entry and its compressed variants, one JSON record
+/// per line. Later records replace earlier ones for the same file, the log is compacted on
+/// startup.
+pub struct Index {
+    file: Mutex<fs::File>,
+}
+
+/// Record of the index, digests are read as JSON values to check their version first
+#[derive(Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum Record<D = Digest> {
+    Put(D),
+    Remove {
+        file_name: String,
+        encoding: Option<Encoding>,
+    },
+}
+
+impl Index {
+    fn open(root: &Path) -> io::Result<Self> {
+        let file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(root.join(INDEX_FILE))?;
+
+        Ok(Index {
+            file: Mutex::new(file),
+        })
+    }
+
+    fn put(&self, digest: &Digest) -> io::Result<()> {
+        self.append(&Record::Put(digest.clone()))
+    }
+
+    fn remove(&self, digest: &Digest) -> io::Result<()> {
+        self.append(&Record::Remove {
+            file_name: digest.file_name.clone(),
+            encoding: digest.encoding,
+        })
+    }
+
+    fn append(&self, record: &Record) -> io::Result<()> {
+        let mut line = serde_json::to_vec(record)?;
+        line.push(b'\n');
+
+        let mut file = self.file.lock().unwrap();
+        file.write_all(&line)?;
+        file.sync_data()
+    }
+}
+
+/// Reads the digests in the index of the entry at `root`. A record torn by a crash ends the
+/// index, the records before it are kept.
+pub fn read(root: &Path) -> io::Result<Vec<Digest>> {
+    let file = match fs::File::open(root.join(INDEX_FILE)) {
+        Ok(file) => file,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err),
+    };
+
+    let mut digests = HashMap::new();
+    for line in io::BufReader::new(file).lines() {
+        let record: Record<serde_json::Value> = match serde_json::from_str(&line?) {
+            Ok(record) => record,
+            Err(err) => {
+                log::warn!(
+                    "Ignoring the rest of the index of {}: {}",
+                    root.to_string_lossy(),
+                    err
+                );
+                break;
+            }
+        };
+
+        match record {
+            Record::Put(value) => match read_digest(root, value) {
+                Ok(digest) => {
+                    digests.insert((digest.file_name.clone(), digest.encoding), digest);
+                }
+                Err(err @ DigestError::UnsupportedVersion(_)) => return Err(io::Error::other(err)),
+                Err(err) => log::warn!(
+                    "Skipping a record in the index of {}: {}",
+                    root.to_string_lossy(),
+                    err
+                ),
+            },
+            Record::Remove {
+                file_name,
+                encoding,
+            } => {
+                digests.remove(&(file_name, encoding));
+            }
+        }
+    }
+
+    Ok(digests.into_values().collect())
+}
+
+/// Reads the digest of a file or variant of the entry at `root` from its record
+fn read_digest(root: &Path, value: serde_json::Value) -> Result<Digest, DigestError> {
+    let encoding = serde_json::from_value(value.get("encoding").cloned().unwrap_or_default())?;
+    let (digest, _) = Digest::from_value(value, &digest_root(root, encoding))?;
+    Ok(digest)
+}
+
+/// Replaces the index of the entry at `root` with one holding `digests`
+fn write(root: &Path, digests: &[Digest]) -> io::Result<()> {
+    let staged = root.join(format!("{}.tmp", INDEX_FILE));
+    let mut file = io::BufWriter::new(fs::File::create(&staged)?);
+    for digest in digests.iter() {
+        serde_json::to_writer(&mut file, &Record::Put(digest))?;
+        file.write_all(b"\n")?;
+    }
+    file.into_inner()?.sync_all()?;
+
+    fs::rename(staged, root.join(INDEX_FILE))?;
+    digest::sync_dir(root)
+}
+
+/// Rewrites the index of the entry at `root` with one record per file that still exists.
+/// Only run on startup, as it races with appends of a running cache.
+pub fn compact(root: &Path) -> io::Result<()> {
+    if !root.is_dir() {
+        return Ok(());
+    }
+
+    let mut digests = read(root)?;
+    digests.retain(|digest| digest.get_file_path().is_file());
+
+    let unindexed = fs::read_dir(root)?
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_type().is_ok_and(|t| t.is_file()))
+        .filter(|entry| {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            !name.starts_with('.')
+                && !name.ends_with(".digest")
+                && !digests
+                    .iter()
+                    .any(|digest| digest.encoding.is_none() && digest.file_name == name)
+        })
+        .count();
+    if unindexed > 0 {
+        log::warn!(
+            "{} files in {} are not in the index, see `bcdn cache index import`",
+            unindexed,
+            root.to_string_lossy()
+        );
+    }
+
+    write(root, &digests)
+}
+
+/// Moves the digests of the entry at `root` into the `.digest` files or the index when
+/// `index` was switched since the last start. Otherwise files are removed by the recovery
+/// for lacking a digest file, or are missing from the new index.
+pub fn convert(root: &Path, index: bool) -> io::Result<()> {
+    if !root.is_dir() || root.join(INDEX_FILE).exists() == index {
+        return Ok(());
+    }
+
+    let (command, count) = if index {
+        ("Imported", import(root)?)
+    } else {
+        ("Exported", export(root)?)
+    };
+    log::info!(
+        "{} {} digests of {} after index was switched",
+        command,
+        count,
+        root.to_string_lossy()
+    );
+
+    Ok(())
+}
+
+/// Moves the `.digest` files of the entry at `root` into its index, returning their number
+fn import(root: &Path) -> io::Result<usize> {
+    let mut digests: HashMap<_, _> = read(root)?
+        .into_iter()
+        .map(|digest| ((digest.file_name.clone(), digest.encoding), digest))
+        .collect();
+
+    let mut sidecars = Vec::new();
+    for encoding in digest_encodings() {
+        let dir = digest_root(root, encoding);
+        if !dir.is_dir() {
+            continue;
+        }
+
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+            let file_name = path.file_name().unwrap().to_string_lossy().into_owned();
+            let data_name = match file_name.strip_suffix(".digest") {
+                Some(name) if !name.starts_with('.') => name,
+                _ => continue,
+            };
+
+            match Digest::for_path(dir.join(data_name)) {
+                Ok(digest) => {
+                    digests.insert((digest.file_name.clone(), encoding), digest);
+                    sidecars.push(path);
+                }
+                Err(err) => log::warn!("Skipping {}: {}", path.to_string_lossy(), err),
+            }
+        }
+    }
+
+    let digests: Vec<Digest> = digests.into_values().collect();
+    write(root, &digests)?;
+    for path in sidecars.iter() {
+        fs::remove_file(path)?;
+    }
+
+    Ok(sidecars.len())
+}
+
+/// Moves the index of the entry at `root` into `.digest` files, returning their number
+fn export(root: &Path) -> io::Result<usize> {
+    let digests = read(root)?;
+    for digest in digests.iter() {
+        digest.write().map_err(io::Error::other)?;
+    }
+
+    match fs::remove_file(root.join(INDEX_FILE)) {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+        _ => {}
+    }
+
+    Ok(digests.len())
+}
+
+/// Directory of the files of an entry with the given encoding
+pub fn digest_root(root: &Path, encoding: Option<Encoding>) -> PathBuf {
+    match encoding {
+        Some(encoding) => root.join(VARIANTS_DIR).join(encoding.as_str()),
+        None => root.to_owned(),
+    }
+}
+
+pub fn digest_encodings() -> impl Iterator<Item = Option<Encoding>> {
+    std::iter::once(None).chain(Encoding::ALL.iter().copied().map(Some))
+}
+
+/// Converts between `.digest` files and the index, while the cache node is stopped
+pub fn run(config: Config, matches: &clap::ArgMatches<'_>) -> io::Result<()> {
+    let (command, matches) = matches.subcommand();
+    let convert = match command {
+        "import" => import,
+        "export" => export,
+        _ => {
+            println!("{}", matches.map_or("", |m| m.usage()));
+            return Ok(());
+        }
+    };
+
+    for (name, root) in entry_roots(&config, matches.and_then(|m| m.value_of("entry")))? {
+        let count = convert(&root)?;
+        println!("{}: {}ed {} digests", name, command, count);
+    }
+
+    Ok(())
+}
+
+/// Names and existing directories of the given entry or all entries, sorted by name
+pub fn entry_roots<'a>(
+    config: &'a Config,
+    entry: Option<&str>,
+) -> io::Result<Vec<(&'a str, PathBuf)>> {
+    let mut names: Vec<&str> = match entry {
+        Some(entry) => vec![
+            config
+                .entries
+                .get_key_value(entry)
+                .ok_or_else(|| io::Error::other(format!("Unknown entry {}", entry)))?
+                .0,
+        ],
+        None => config.entries.keys().map(|name| name.as_str()).collect(),
+    };
+    names.sort_unstable();
+
+    Ok(names
+        .into_iter()
+        .map(|name| (name, Path::new(&config.cache.root_path).join(name)))
+        .filter(|(_, root)| root.is_dir())
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::test_dir::TestDir;
+
+    #[test]
+    fn test_index() {
+        let root = TestDir::new("index");
+
+        let digest = |name: &str| {
+            fs::write(root.join(name), name).unwrap();
+            Digest::new(
+                root.join(name),
+                "text/plain",
+                Vec::new(),
+                blake3::hash(b"x"),
+            )
+        };
+        let (a, b) = (digest("a"), digest("b"));
+
+        let index = Index::open(&root).unwrap();
+        index.put(&a).unwrap();
+        index.put(&b).unwrap();
+        index.remove(&a).unwrap();
+        index.put(&b).unwrap();
+        fs::OpenOptions::new()
+            .append(true)
+            .open(root.join(INDEX_FILE))
+            .unwrap()
+            .write_all(b"{\"put\":{\"si")
+            .unwrap();
+
+        let digests = read(&root).unwrap();
+        assert_eq!(digests.len(), 1);
+        assert_eq!(digests[0].file_name, "b");
+        assert_eq!(digests[0].get_file_path(), root.join("b"));
+
+        assert_eq!(export(&root).unwrap(), 1);
+        assert!(root.join("b.digest").is_file());
+        assert_eq!(import(&root).unwrap(), 1);
+        assert!(!root.join("b.digest").exists());
+        assert_eq!(read(&root).unwrap().len(), 1);
+
+        convert(&root, true).unwrap();
+        assert!(root.join(INDEX_FILE).is_file());
+        convert(&root, false).unwrap();
+        assert!(root.join("b.digest").is_file());
+        assert!(!root.join(INDEX_FILE).exists());
+        convert(&root, true).unwrap();
+        assert!(!root.join("b.digest").exists());
+        assert_eq!(read(&root).unwrap().len(), 1);
+    }
+}
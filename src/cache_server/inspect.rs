@@ -0,0 +1,195 @@
+use super::cache::{AccessStats, Cache};
+use super::Caches;
+use crate::config::Encoding;
+use crate::digest::Digest;
+use actix_web::error::BlockingError;
+use actix_web::{web, HttpResponse};
+use globset::Glob;
+use serde::{Deserialize, Serialize};
+
+const DEFAULT_LIMIT: usize = 100;
+const MAX_LIMIT: usize = 1000;
+
+#[derive(Serialize)]
+pub struct EntryInfo {
+    pub name: String,
+    pub files: usize,
+    /// Size of all files including their compressed variants
+    pub size: u64,
+}
+
+/// A cached file as returned by the admin API
+#[derive(Serialize)]
+pub struct FileInfo {
+    #[serde(flatten)]
+    pub digest: Digest,
+    pub variants: Vec<Encoding>,
+    pub access: AccessStats,
+}
+
+#[derive(Serialize)]
+pub struct FileList {
+    /// Number of files matching the filter
+    pub total: usize,
+    pub offset: usize,
+    pub files: Vec<FileInfo>,
+}
+
+#[derive(Deserialize)]
+pub struct ListQuery {
+    glob: Option<String>,
+    #[serde(default)]
+    offset: usize,
+    limit: Option<usize>,
+}
+
+#[derive(Serialize)]
+pub struct VerifyResult {
+    pub file: String,
+    /// `None` for the original file
+    pub encoding: Option<Encoding>,
+    pub valid: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+pub async fn entries(caches: web::Data<Caches>) -> HttpResponse {
+    let mut res = Vec::new();
+
+    for cache in caches.all() {
+        let mut size = 0;
+        let files = cache.list().await;
+
+        for digest in files.iter() {
+            size += digest.size;
+            size += cache
+                .variants(&digest.file_name)
+                .await
+                .iter()
+                .map(|v| v.size)
+                .sum::<u64>();
+        }
+
+        res.push(EntryInfo {
+            name: cache.name.clone(),
+            files: files.len(),
+            size,
+        });
+    }
+
+    HttpResponse::Ok().json(res)
+}
+
+pub async fn files(
+    entry: web::Path<String>,
+    query: web::Query<ListQuery>,
+    caches: web::Data<Caches>,
+) -> HttpResponse {
+    let cache = match caches.get(entry.as_str()) {
+        Some(cache) => cache,
+        None => return HttpResponse::NotFound().body("Unknown entry"),
+    };
+
+    let glob = match query.glob {
+        Some(ref glob) => match Glob::new(glob) {
+            Ok(glob) => Some(glob.compile_matcher()),
+            Err(err) => return HttpResponse::BadRequest().body(err.to_string()),
+        },
+        None => None,
+    };
+
+    let matching: Vec<Digest> = cache
+        .list()
+        .await
+        .into_iter()
+        .filter(|d| glob.as_ref().is_none_or(|g| g.is_match(&d.file_name)))
+        .collect();
+
+    let limit = query.limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT);
+    let mut files = Vec::new();
+    for digest in matching.iter().skip(query.offset).take(limit) {
+        files.push(file_info(&cache, digest.clone()).await);
+    }
+
+    HttpResponse::Ok().json(FileList {
+        total: matching.len(),
+        offset: query.offset,
+        files,
+    })
+}
+
+pub async fn file(path: web::Path<(String, String)>, caches: web::Data<Caches>) -> HttpResponse {
+    let (cache, digest) = match lookup(&path, &caches).await {
+        Ok(found) => found,
+        Err(resp) => return resp,
+    };
+
+    HttpResponse::Ok().json(file_info(&cache, digest).await)
+}
+
+/// Re-hashes the file and its compressed variants and compares them with their digests
+pub async fn verify(path: web::Path<(String, String)>, caches: web::Data<Caches>) -> HttpResponse {
+    let (cache, digest) = match lookup(&path, &caches).await {
+        Ok(found) => found,
+        Err(resp) => return resp,
+    };
+
+    let mut digests = vec![digest];
+    digests.extend(cache.variants(&path.1).await);
+
+    let mut res = Vec::new();
+    for digest in digests {
+        let file = digest.file_name.clone();
+        let encoding = digest.encoding;
+
+        let error = match web::block(move || digest.verify()).await {
+            Ok(()) => None,
+            Err(BlockingError::Error(err)) => Some(err.to_string()),
+            Err(BlockingError::Canceled) => Some("Verification was canceled".to_owned()),
+        };
+
+        if let Some(ref error) = error {
+            log::warn!("Verifying {}/{} failed: {}", cache.name, file, error);
+        }
+
+        res.push(VerifyResult {
+            file,
+            encoding,
+            valid: error.is_none(),
+            error,
+        });
+    }
+
+    HttpResponse::Ok().json(res)
+}
+
+async fn lookup(
+    path: &(String, String),
+    caches: &Caches,
+) -> Result<(web::Data<Cache>, Digest), HttpResponse> {
+    let (entry, name) = path;
+
+    let cache = caches
+        .get(entry)
+        .ok_or_else(|| HttpResponse::NotFound().body("Unknown entry"))?;
+
+    match cache.lookup(name).await {
+        Some(digest) => Ok((cache, digest)),
+        None => Err(HttpResponse::NotFound().body("Not cached")),
+    }
+}
+
+async fn file_info(cache: &Cache, digest: Digest) -> FileInfo {
+    let variants = cache
+        .variants(&digest.file_name)
+        .await
+        .iter()
+        .filter_map(|v| v.encoding)
+        .collect();
+
+    FileInfo {
+        access: cache.access_stats(&digest.file_name),
+        variants,
+        digest,
+    }
+}
@@ -0,0 +1,19 @@
+use crate::config::CacheConfig;
+use crate::util::throttle::{self, TokenBucket};
+use std::sync::Arc;
+
+/// Limits shared by all entries of the node
+#[derive(Clone, Default)]
+pub struct NodeLimits {
+    pub fill: Option<Arc<TokenBucket>>,
+    pub serve: Option<Arc<TokenBucket>>,
+}
+
+impl NodeLimits {
+    pub fn new(config: &CacheConfig) -> Self {
+        NodeLimits {
+            fill: throttle::bucket(config.bandwidth.fill),
+            serve: throttle::bucket(config.bandwidth.serve),
+        }
+    }
+}
@@ -0,0 +1,82 @@
+use percent_encoding::percent_decode_str;
+
+/// Extracts the names of the files linked from an HTML directory listing as generated by
+/// nginx, Apache and most other web servers.
+///
+/// Only relative links to files in the same directory are taken into account.
+pub fn parse(html: &str) -> Vec<String> {
+    let mut res = Vec::new();
+    // Lowercasing ASCII keeps all byte offsets intact
+    let lower = html.to_ascii_lowercase();
+    let mut offset = 0;
+
+    while let Some(pos) = lower[offset..].find("href=") {
+        let start = offset + pos + "href=".len();
+        let rest = &html[start..];
+
+        let (value, len) = match rest.chars().next() {
+            Some(quote) if quote == '"' || quote == '\'' => match rest[1..].find(quote) {
+                Some(end) => (&rest[1..=end], end + 2),
+                None => break,
+            },
+            _ => {
+                let end = rest
+                    .find(|c: char| c.is_whitespace() || c == '>')
+                    .unwrap_or(rest.len());
+                (&rest[..end], end)
+            }
+        };
+
+        offset = start + len;
+
+        if let Some(name) = file_name(value) {
+            if !res.contains(&name) {
+                res.push(name);
+            }
+        }
+    }
+
+    res
+}
+
+fn file_name(href: &str) -> Option<String> {
+    let href = href.split(['?', '#']).next()?;
+    let href = href.strip_prefix("./").unwrap_or(href);
+
+    if href.is_empty() || href.contains('/') || href.contains(':') || href == ".." {
+        return None;
+    }
+
+    let name = percent_decode_str(href).decode_utf8().ok()?;
+
+    if name.contains('/') {
+        None
+    } else {
+        Some(name.into_owned())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let html = r#"<html><head><title>Index of /radio/</title></head><body>
+<h1>Index of /radio/</h1><hr><pre><a href="../">../</a>
+<a href="sub/">sub/</a>
+<a href="Folge%2001.m4a">Folge 01.m4a</a>   01-Jan-2020 10:00   1234
+<a HREF='folge02.m4a?download=1'>folge02.m4a</a>
+<a href=./folge03.m4a>folge03.m4a</a>
+<a href="https://example.com/other.m4a">other</a>
+<a href="/absolute.m4a">absolute</a>
+<a href="?C=N;O=D">Name</a>
+<a href="Folge%2001.m4a">again</a>
+</pre><hr></body></html>"#;
+
+        assert_eq!(
+            parse(html),
+            vec!["Folge 01.m4a", "folge02.m4a", "folge03.m4a"]
+        );
+    }
+}
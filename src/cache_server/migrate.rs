@@ -0,0 +1,91 @@
+use super::index::{self, digest_encodings, digest_root, entry_roots};
+use crate::config::Config;
+use crate::digest::Digest;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// Upgrades all digests of a stopped cache node to the current version of the format
+pub fn run(config: Config, matches: &clap::ArgMatches<'_>) -> io::Result<()> {
+    for (name, root) in entry_roots(&config, matches.value_of("entry"))? {
+        let (mut migrated, mut failed) = (0, 0);
+
+        for encoding in digest_encodings() {
+            let dir = digest_root(&root, encoding);
+            if dir.is_dir() {
+                let (dir_migrated, dir_failed) = migrate_dir(&dir)?;
+                migrated += dir_migrated;
+                failed += dir_failed;
+            }
+        }
+
+        if config.cache.index {
+            // Rewrites every record in the current version
+            index::compact(&root)?;
+        }
+
+        println!("{}: migrated {} digests, {} failed", name, migrated, failed);
+    }
+
+    Ok(())
+}
+
+/// Upgrades the `.digest` files in `dir`, returning the number of migrated and failed ones.
+/// Digests of partial downloads are hidden and left to be migrated when resumed.
+fn migrate_dir(dir: &Path) -> io::Result<(usize, usize)> {
+    let (mut migrated, mut failed) = (0, 0);
+
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let file_name = path.file_name().unwrap().to_string_lossy().into_owned();
+        let data_name = match file_name.strip_suffix(".digest") {
+            Some(name) if !name.starts_with('.') => name,
+            _ => continue,
+        };
+
+        match Digest::upgrade(dir.join(data_name)) {
+            Ok(true) => migrated += 1,
+            Ok(false) => {}
+            Err(err) => {
+                println!("{}: {}", path.to_string_lossy(), err);
+                failed += 1;
+            }
+        }
+    }
+
+    Ok((migrated, failed))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::test_dir::TestDir;
+
+    #[test]
+    fn test_migrate_dir() {
+        let dir = TestDir::new("migrate");
+
+        let write_v1 = |name: &str| {
+            fs::write(dir.join(name), "a").unwrap();
+            let digest = serde_json::json!({
+                "version": 1,
+                "size": 1,
+                "downloaded": 1,
+                "file_name": name,
+                "content_type": "text/plain",
+                "hash": blake3::hash(b"a").to_hex().to_string(),
+            });
+            fs::write(dir.join(format!("{}.digest", name)), digest.to_string()).unwrap();
+        };
+        write_v1("a");
+        write_v1(".x.download");
+        let partial = fs::read_to_string(dir.join(".x.download.digest")).unwrap();
+
+        assert_eq!(migrate_dir(&dir).unwrap(), (1, 0));
+        assert!(!Digest::upgrade(dir.join("a")).unwrap());
+        assert_eq!(
+            fs::read_to_string(dir.join(".x.download.digest")).unwrap(),
+            partial
+        );
+    }
+}
@@ -1,49 +1,301 @@
-use crate::config::Config;
-use actix_web::{http, web, App, Either, HttpResponse, HttpServer, Responder};
+use crate::config::{self, Config, Loader, Role};
+use crate::util::access_log::{AccessInfo, AccessLog};
+use crate::util::client_limits::{self, Limiters};
+use actix_web::dev::Service;
+use actix_web::http::{header, StatusCode};
+use actix_web::{middleware, web, App, Either, HttpRequest, HttpResponse, HttpServer, Responder};
 
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+
+mod admin;
 mod cache;
+mod client;
+mod compression;
 mod download;
-use cache::{Cache, CacheResult};
+mod headers;
+mod index;
+mod inspect;
+mod limits;
+mod listing;
+mod migrate;
+mod negative;
+mod origins;
+mod purge;
+mod recovery;
+mod registry;
+mod shutdown;
+mod warm;
+use cache::{Cache, CacheResult, Outcome, ONLY_IF_CACHED};
+use download::DownloadError;
+use shutdown::SHUTDOWN;
+
+pub use index::run as index;
+pub use migrate::run as migrate;
+pub use registry::Caches;
+pub use warm::run as warm;
+
+/// Header naming the reason of an error response
+const X_CACHE_ERROR: &str = "x-cache-error";
+
+/// Seconds beyond the shutdown timeout that interrupted downloads get to save their progress
+/// and answer their requests
+const SHUTDOWN_GRACE: u64 = 5;
 
 #[actix_rt::main]
-pub async fn run(config: Config, _matches: &clap::ArgMatches<'_>) -> std::io::Result<()> {
+pub async fn run(config: Config, loader: Loader) -> io::Result<()> {
     let bind = config.cache.bind.clone();
 
     log::info!("Starting cache node at {}...", bind);
 
-    HttpServer::new(move || {
-        let config = config.clone();
-        App::new().service(web::scope("/c/v1").configure(|cfg| configure(&config, cfg)))
-        // .service(cache_scope)
+    let admin_token = config.cache.admin_token.clone();
+    let shutdown_timeout = Duration::from_secs(config.cache.shutdown_timeout);
+    let node_id = config
+        .cache
+        .node_id
+        .clone()
+        .map(|id| (config.cache.node_id_header.clone(), id));
+    let access_log = match config.cache.access_log {
+        Some(ref log) => Some(Arc::new(AccessLog::open(log)?)),
+        None => None,
+    };
+
+    let limiters = Arc::new(Limiters::new(&config.cache.client_limits, &config));
+    let caches = Caches::new(config).map_err(|err| io::Error::other(err.to_string()))?;
+    let caches = web::Data::new(caches);
+    {
+        let (caches, limiters) = (caches.clone(), limiters.clone());
+        config::reload_on_hangup(loader, &[Role::Cache], move |config| {
+            limiters.reload(&config.cache.client_limits, &config);
+            let caches = caches.clone();
+            async move { caches.reload(config).await }
+        });
+    }
+
+    let server = HttpServer::new(move || {
+        let (access_log, limiters) = (access_log.clone(), limiters.clone());
+        let mut default_headers = middleware::DefaultHeaders::new();
+        if let Some((ref name, ref id)) = node_id {
+            default_headers = default_headers.header(name.as_str(), id.as_str());
+        }
+
+        App::new()
+            .wrap(middleware::Compress::default())
+            .wrap(default_headers)
+            .wrap_fn(move |req, srv| {
+                client_limits::limit(&limiters, req, srv, |reason| {
+                    error_response(StatusCode::TOO_MANY_REQUESTS, reason)
+                })
+            })
+            .wrap_fn(move |req, srv| {
+                let pending = access_log.as_ref().map(|log| log.start(&req));
+                let fut = srv.call(req);
+                async move {
+                    let res = fut.await?;
+                    if let Some(pending) = pending {
+                        pending.finish(&res);
+                    }
+                    Ok(res)
+                }
+            })
+            .app_data(caches.clone())
+            .route("/metrics", web::get().to(metrics))
+            .route("/c/v1/{entry}/f/{filename}", web::get().to(data))
+            .route("/c/v1/{entry}/f/{filename}", web::head().to(head))
+            .configure(|cfg| {
+                if let Some(ref token) = admin_token {
+                    admin::configure(token, cfg)
+                }
+            })
     })
     .bind(bind)?
-    .run()
-    .await
+    .disable_signals()
+    .shutdown_timeout(shutdown_timeout.as_secs() + SHUTDOWN_GRACE)
+    .run();
+
+    {
+        let server = server.clone();
+        actix_rt::spawn(async move {
+            shutdown::signaled().await;
+            log::info!(
+                "Shutting down, waiting up to {}s for running downloads...",
+                shutdown_timeout.as_secs()
+            );
+            SHUTDOWN.begin(shutdown_timeout);
+            server.stop(true).await;
+        });
+    }
+
+    server.await
 }
 
-fn configure(config: &Config, cfg: &mut web::ServiceConfig) {
-    for (name, _entry) in &config.entries {
-        let cache = Cache::new(name, &config);
-        let own_scope = web::scope(name)
-            .data(cache)
-            .route("/f/{filename}", web::get().to(data));
+async fn data(
+    req: HttpRequest,
+    path: web::Path<(String, String)>,
+    caches: web::Data<Caches>,
+) -> impl Responder {
+    let (entry, filename) = path.as_ref();
+    let cache = match caches.get(entry) {
+        Some(cache) => cache,
+        None => return Either::B(error_response(StatusCode::NOT_FOUND, "unknown_entry")),
+    };
+    let mut info = AccessInfo::new(&cache.name, filename);
+    let cached_only = only_if_cached(&req);
+    let mut error = None;
+
+    // The file may get purged between looking it up and opening it, in that case it is
+    // looked up (and downloaded) once more
+    for _ in 0..2 {
+        let result = if cached_only {
+            match cache.lookup(filename).await {
+                Some(digest) => CacheResult::Ok(digest, Outcome::Hit),
+                None => break,
+            }
+        } else {
+            cache.get(filename).await
+        };
 
-        cfg.service(own_scope);
+        match result {
+            CacheResult::Ok(digest, outcome) => {
+                match cache.serve(&digest, outcome, accept_encoding(&req)).await {
+                    Ok(file) => {
+                        info.outcome = Some(outcome.as_str());
+                        info.record(&req);
+                        return Either::A(file);
+                    }
+                    Err(err) => log::warn!("Failed to open {}/{}: {}", cache.name, filename, err),
+                }
+            }
+            CacheResult::DownloadError(err) => {
+                error = Some(download_failed("download", &cache, filename, &err));
+                break;
+            }
+            CacheResult::NotFound => break,
+        }
     }
+
+    if cache.is_match(filename) {
+        info.outcome = Some(Outcome::Miss.as_str());
+    }
+    info.record(&req);
+
+    Either::B(match error {
+        Some(resp) => resp,
+        None if cached_only => error_response(StatusCode::GATEWAY_TIMEOUT, "not_cached"),
+        None => error_response(StatusCode::NOT_FOUND, "not_found"),
+    })
 }
 
-async fn data(path: web::Path<String>, cache: web::Data<Cache>) -> impl Responder {
-    match cache.as_ref().get(path.as_ref()).await {
-        CacheResult::Ok(digest) => Either::A(digest.serve()),
-        CacheResult::NotCached { redirect, in_work } => {
-            if !in_work {}
+async fn head(
+    req: HttpRequest,
+    path: web::Path<(String, String)>,
+    caches: web::Data<Caches>,
+) -> HttpResponse {
+    let (entry, filename) = path.as_ref();
+    let cache = match caches.get(entry) {
+        Some(cache) => cache,
+        None => return error_response(StatusCode::NOT_FOUND, "unknown_entry"),
+    };
+    let mut info = AccessInfo::new(&cache.name, filename);
 
-            Either::B(
-                HttpResponse::TemporaryRedirect()
-                    .header(http::header::LOCATION, redirect.to_string())
-                    .body(format!("In work: {}", in_work)),
-            )
+    let result = if only_if_cached(&req) {
+        match cache.lookup(filename).await {
+            Some(digest) => CacheResult::Ok(digest, Outcome::Hit),
+            None => {
+                info.record(&req);
+                return error_response(StatusCode::GATEWAY_TIMEOUT, "not_cached");
+            }
         }
-        _ => Either::B(HttpResponse::NotFound().body("Not found")),
+    } else if cache.head_fill() {
+        cache.get(filename).await
+    } else if let Some(digest) = cache.lookup(filename).await {
+        CacheResult::Ok(digest, Outcome::Hit)
+    } else if cache.is_match(filename) {
+        info.outcome = Some(Outcome::Miss.as_str());
+        info.record(&req);
+
+        return match cache.head_origin(filename).await {
+            Ok(resp) => resp,
+            Err(err) => download_failed("probe", &cache, filename, &err),
+        };
+    } else {
+        CacheResult::NotFound
+    };
+
+    info.outcome = match result {
+        CacheResult::Ok(_, outcome) => Some(outcome.as_str()),
+        CacheResult::DownloadError(_) => Some(Outcome::Miss.as_str()),
+        CacheResult::NotFound => None,
+    };
+    info.record(&req);
+
+    match result {
+        CacheResult::Ok(digest, outcome) => {
+            cache.head(&digest, outcome, accept_encoding(&req)).await
+        }
+        CacheResult::DownloadError(err) => download_failed("download", &cache, filename, &err),
+        CacheResult::NotFound => error_response(StatusCode::NOT_FOUND, "not_found"),
+    }
+}
+
+/// Logs a failed request to the origin and answers with the matching error
+fn download_failed(
+    action: &str,
+    cache: &Cache,
+    filename: &str,
+    err: &DownloadError,
+) -> HttpResponse {
+    match err.origin_status() {
+        Some(status) if status.is_client_error() => {
+            log::debug!("Failed to {} {}/{}: {}", action, cache.name, filename, err)
+        }
+        _ => log::error!(
+            "Failed to {} {}/{}: {:?}",
+            action,
+            cache.name,
+            filename,
+            err
+        ),
+    }
+
+    let (status, reason) = err.client_status(cache.forward_forbidden());
+    error_response(status, reason)
+}
+
+/// Error response with a short plain text body, `reason` is also sent in `X-Cache-Error`
+fn error_response(status: StatusCode, reason: &str) -> HttpResponse {
+    HttpResponse::build(status)
+        .header(X_CACHE_ERROR, reason)
+        .content_type("text/plain; charset=utf-8")
+        .body(format!(
+            "{} ({})\n",
+            status.canonical_reason().unwrap_or("Error"),
+            reason
+        ))
+}
+
+/// Whether the request asks to be answered from the cache only, as peers do
+fn only_if_cached(req: &HttpRequest) -> bool {
+    req.headers()
+        .get(header::CACHE_CONTROL)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| {
+            v.split(',')
+                .any(|directive| directive.trim().eq_ignore_ascii_case(ONLY_IF_CACHED))
+        })
+}
+
+fn accept_encoding(req: &HttpRequest) -> Option<&str> {
+    req.headers()
+        .get(header::ACCEPT_ENCODING)
+        .and_then(|v| v.to_str().ok())
+}
+
+async fn metrics(caches: web::Data<Caches>) -> HttpResponse {
+    for cache in caches.all() {
+        cache.update_metrics().await;
     }
+
+    crate::metrics::render()
 }
@@ -0,0 +1,111 @@
+use crate::config::NegativeCacheConfig;
+use actix_web::http::StatusCode;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Upper bound of remembered failures per entry, so scanning traffic cannot grow the map
+/// without limit
+const MAX_ENTRIES: usize = 10_000;
+
+/// Remembers files the origin failed to deliver, so requests for them are answered with
+/// the status of the origin without asking it again until the TTL expires
+pub struct NegativeCache {
+    not_found_ttl: Duration,
+    error_ttl: Duration,
+    entries: Arc<Mutex<HashMap<String, (StatusCode, Instant)>>>,
+}
+
+impl NegativeCache {
+    pub fn new(config: &NegativeCacheConfig) -> Self {
+        NegativeCache {
+            not_found_ttl: Duration::from_secs(config.not_found_ttl),
+            error_ttl: Duration::from_secs(config.error_ttl),
+            entries: Arc::default(),
+        }
+    }
+
+    /// Cache with the TTLs of `config` for new failures, sharing the remembered ones
+    pub fn reconfigure(&self, config: &NegativeCacheConfig) -> Self {
+        NegativeCache {
+            entries: self.entries.clone(),
+            ..Self::new(config)
+        }
+    }
+
+    /// Returns the status of the origin if a failure for the file is remembered
+    pub fn get(&self, name: &str) -> Option<StatusCode> {
+        self.get_at(name, Instant::now())
+    }
+
+    fn get_at(&self, name: &str, now: Instant) -> Option<StatusCode> {
+        let mut entries = self.entries.lock().unwrap();
+
+        match entries.get(name) {
+            Some(&(status, expires)) if expires > now => Some(status),
+            Some(_) => {
+                entries.remove(name);
+                None
+            }
+            None => None,
+        }
+    }
+
+    /// Remembers the status of the origin for the file, if it is a status to cache
+    pub fn insert(&self, name: &str, status: StatusCode) {
+        let ttl = match status {
+            StatusCode::NOT_FOUND | StatusCode::GONE => self.not_found_ttl,
+            status if status.is_server_error() => self.error_ttl,
+            _ => return,
+        };
+
+        if ttl == Duration::from_secs(0) {
+            return;
+        }
+
+        let now = Instant::now();
+        let mut entries = self.entries.lock().unwrap();
+
+        if entries.len() >= MAX_ENTRIES {
+            entries.retain(|_, (_, expires)| *expires > now);
+            if entries.len() >= MAX_ENTRIES {
+                return;
+            }
+        }
+
+        entries.insert(name.to_owned(), (status, now + ttl));
+    }
+
+    pub fn remove(&self, name: &str) {
+        self.entries.lock().unwrap().remove(name);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_negative_cache() {
+        let cache = NegativeCache::new(&NegativeCacheConfig {
+            not_found_ttl: 60,
+            error_ttl: 0,
+        });
+
+        cache.insert("a", StatusCode::NOT_FOUND);
+        cache.insert("b", StatusCode::FORBIDDEN);
+        cache.insert("c", StatusCode::BAD_GATEWAY);
+
+        assert_eq!(cache.get("a"), Some(StatusCode::NOT_FOUND));
+        assert_eq!(cache.get("b"), None);
+        assert_eq!(cache.get("c"), None);
+
+        let later = Instant::now() + Duration::from_secs(61);
+        assert_eq!(cache.get_at("a", later), None);
+        assert_eq!(cache.get("a"), None);
+
+        cache.insert("a", StatusCode::GONE);
+        cache.remove("a");
+        assert_eq!(cache.get("a"), None);
+    }
+}
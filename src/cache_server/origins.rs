@@ -0,0 +1,238 @@
+use super::download::DownloadError;
+use crate::config::{ConfigError, Entry, OriginStrategy};
+use rand::distributions::{Distribution, WeightedIndex};
+use std::future::Future;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use url::Url;
+
+/// The origins of an entry, i.e. its `base_url` and mirrors or its parent cache nodes,
+/// together with their health
+pub struct Origins {
+    origins: Vec<Origin>,
+    strategy: OriginStrategy,
+    max_failures: u32,
+    down_time: Duration,
+    next: AtomicUsize,
+}
+
+pub struct Origin {
+    pub url: Url,
+    weight: u32,
+    state: Mutex<Health>,
+}
+
+#[derive(Default)]
+struct Health {
+    failures: u32,
+    down_until: Option<Instant>,
+}
+
+impl Origins {
+    pub fn new(entry: &Entry) -> Result<Self, ConfigError> {
+        let mut origins = vec![Origin::new(
+            Url::parse(&entry.base_url)?,
+            entry.origin.weight,
+        )];
+        for mirror in entry.origin.mirrors.iter() {
+            origins.push(Origin::new(Url::parse(&mirror.url)?, mirror.weight));
+        }
+
+        Ok(Self::with_origins(origins, entry.origin.strategy, entry))
+    }
+
+    /// The entry `name` on the given cache nodes, tried in order
+    pub fn nodes(nodes: &[Url], name: &str, entry: &Entry) -> Result<Self, ConfigError> {
+        let mut origins = Vec::new();
+        for node in nodes.iter() {
+            let url = node.join(&format!("c/v1/{}/f/", name))?;
+            origins.push(Origin::new(url, 1));
+        }
+
+        Ok(Self::with_origins(origins, OriginStrategy::Failover, entry))
+    }
+
+    fn with_origins(origins: Vec<Origin>, strategy: OriginStrategy, entry: &Entry) -> Self {
+        Origins {
+            origins,
+            strategy,
+            max_failures: entry.origin.max_failures.max(1),
+            down_time: Duration::from_secs(entry.origin.down_time),
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    /// Returns the origins in the order to try them for the next request, origins that
+    /// are marked down come last
+    pub fn candidates(&self) -> Vec<&Origin> {
+        let now = Instant::now();
+        let (mut up, down): (Vec<&Origin>, Vec<&Origin>) =
+            self.origins.iter().partition(|origin| origin.is_up(now));
+
+        if !up.is_empty() {
+            let first = match self.strategy {
+                OriginStrategy::Failover => 0,
+                OriginStrategy::RoundRobin => self.next.fetch_add(1, Ordering::Relaxed) % up.len(),
+                OriginStrategy::Weighted => {
+                    WeightedIndex::new(up.iter().map(|origin| origin.weight))
+                        .map(|dist| dist.sample(&mut rand::thread_rng()))
+                        .unwrap_or(0)
+                }
+            };
+            up.rotate_left(first);
+        }
+
+        up.extend(down);
+        up
+    }
+
+    /// Returns the origins that are not marked down
+    pub fn available(&self) -> Vec<&Origin> {
+        let now = Instant::now();
+        self.origins
+            .iter()
+            .filter(|origin| origin.is_up(now))
+            .collect()
+    }
+
+    /// Runs `request` with the URL of `path` on each candidate origin until one succeeds.
+    ///
+    /// Failures of the origin count towards marking it down. On other errors, like a missing
+    /// file, the next origin is tried as well, local errors are returned directly.
+    pub async fn request<T, F, Fut>(&self, path: &str, request: F) -> Result<T, DownloadError>
+    where
+        F: Fn(Url) -> Fut,
+        Fut: Future<Output = Result<T, DownloadError>>,
+    {
+        let mut last_err = None;
+
+        for origin in self.candidates() {
+            let url = match origin.url.join(path) {
+                Ok(url) => url,
+                Err(_) => return Err(DownloadError::PathError),
+            };
+
+            match request(url).await {
+                Ok(res) => {
+                    origin.succeeded();
+                    return Ok(res);
+                }
+                Err(err) if err.is_local() => return Err(err),
+                Err(err) => {
+                    if err.is_origin_failure() {
+                        self.failed(origin);
+                    }
+                    log::debug!("Request to {} failed: {}", origin.url, err);
+                    // An answer like a missing file is more telling than a failed origin
+                    if last_err
+                        .as_ref()
+                        .is_none_or(DownloadError::is_origin_failure)
+                    {
+                        last_err = Some(err);
+                    }
+                }
+            }
+        }
+
+        Err(last_err.unwrap_or(DownloadError::PathError))
+    }
+
+    /// Counts a failure of `origin`, marking it down after `max_failures` in a row
+    pub fn failed(&self, origin: &Origin) {
+        let mut state = origin.state.lock().unwrap();
+        state.failures += 1;
+
+        if state.failures >= self.max_failures {
+            log::warn!(
+                "Marking origin {} down for {}s after {} failures",
+                origin.url,
+                self.down_time.as_secs(),
+                state.failures
+            );
+            state.failures = 0;
+            state.down_until = Some(Instant::now() + self.down_time);
+        }
+    }
+}
+
+impl Origin {
+    fn new(url: Url, weight: u32) -> Self {
+        Origin {
+            url,
+            weight,
+            state: Mutex::new(Health::default()),
+        }
+    }
+
+    fn is_up(&self, now: Instant) -> bool {
+        let state = self.state.lock().unwrap();
+        state.down_until.is_none_or(|until| until <= now)
+    }
+
+    pub fn succeeded(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.failures = 0;
+        state.down_until = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn origins(strategy: &str) -> Origins {
+        let entry: Entry = toml::from_str(&format!(
+            r#"
+base_url = "http://a/"
+
+[origin]
+strategy = "{}"
+max_failures = 2
+mirrors = [{{ url = "http://b/" }}, {{ url = "http://c/", weight = 0 }}]
+"#,
+            strategy
+        ))
+        .unwrap();
+
+        Origins::new(&entry).unwrap()
+    }
+
+    fn hosts(origins: &Origins) -> Vec<&str> {
+        origins
+            .candidates()
+            .into_iter()
+            .map(|origin| origin.url.host_str().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_failover() {
+        let origins = origins("failover");
+        assert_eq!(hosts(&origins), vec!["a", "b", "c"]);
+
+        origins.failed(&origins.origins[0]);
+        assert_eq!(hosts(&origins), vec!["a", "b", "c"]);
+        origins.failed(&origins.origins[0]);
+        assert_eq!(hosts(&origins), vec!["b", "c", "a"]);
+
+        origins.origins[0].succeeded();
+        assert_eq!(hosts(&origins), vec!["a", "b", "c"]);
+    }
+
+    #[test]
+    fn test_round_robin() {
+        let origins = origins("round_robin");
+        assert_eq!(hosts(&origins), vec!["a", "b", "c"]);
+        assert_eq!(hosts(&origins), vec!["b", "c", "a"]);
+        assert_eq!(hosts(&origins), vec!["c", "a", "b"]);
+    }
+
+    #[test]
+    fn test_weighted() {
+        let origins = origins("weighted");
+        for _ in 0..20 {
+            assert_ne!(hosts(&origins)[0], "c");
+        }
+    }
+}
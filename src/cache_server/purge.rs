@@ -0,0 +1,84 @@
+use super::Caches;
+use actix_web::{web, HttpResponse};
+use globset::Glob;
+use serde::{Deserialize, Serialize};
+use std::io;
+
+/// Selects the files to purge, e.g. `{"glob": "*.m4a"}` or `"all"`
+#[derive(Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PurgeRequest {
+    File(String),
+    Glob(String),
+    Hash(String),
+    All,
+}
+
+#[derive(Serialize)]
+pub struct PurgeResult {
+    pub purged: Vec<String>,
+}
+
+pub async fn purge(
+    entry: web::Path<String>,
+    request: web::Json<PurgeRequest>,
+    caches: web::Data<Caches>,
+) -> HttpResponse {
+    let cache = match caches.get(entry.as_str()) {
+        Some(cache) => cache,
+        None => return HttpResponse::NotFound().body("Unknown entry"),
+    };
+
+    let res = match request.into_inner() {
+        PurgeRequest::File(name) => {
+            cache
+                .purge(&name)
+                .await
+                .map(|purged| if purged { vec![name] } else { Vec::new() })
+        }
+        PurgeRequest::Glob(glob) => {
+            let glob = match Glob::new(&glob) {
+                Ok(glob) => glob.compile_matcher(),
+                Err(err) => return HttpResponse::BadRequest().body(err.to_string()),
+            };
+            cache.purge_where(|d| glob.is_match(&d.file_name)).await
+        }
+        PurgeRequest::Hash(hash) => {
+            let hash = hash.to_ascii_lowercase();
+            cache
+                .purge_where(|d| d.hash().to_hex().as_str() == hash)
+                .await
+        }
+        PurgeRequest::All => cache.purge_where(|_| true).await,
+    };
+
+    respond(res)
+}
+
+pub async fn purge_file(
+    path: web::Path<(String, String)>,
+    caches: web::Data<Caches>,
+) -> HttpResponse {
+    let (entry, name) = path.into_inner();
+
+    let cache = match caches.get(&entry) {
+        Some(cache) => cache,
+        None => return HttpResponse::NotFound().body("Unknown entry"),
+    };
+
+    match cache.purge(&name).await {
+        Ok(true) => respond(Ok(vec![name])),
+        Ok(false) => HttpResponse::NotFound().body("Not cached"),
+        Err(err) => respond(Err(err)),
+    }
+}
+
+fn respond(res: io::Result<Vec<String>>) -> HttpResponse {
+    match res {
+        Ok(purged) => HttpResponse::Ok().json(PurgeResult { purged }),
+        Err(err) => {
+            log::error!("Failed to purge: {}", err);
+            HttpResponse::InternalServerError().body(err.to_string())
+        }
+    }
+}
@@ -0,0 +1,121 @@
+use super::compression::{COMPRESS_SUFFIX, VARIANTS_DIR};
+use super::download::DOWNLOAD_SUFFIX;
+use crate::config::Encoding;
+use crate::digest::{Digest, STAGED_DIGEST_SUFFIX};
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// Brings the directory of an entry and its variants back into a consistent state after a
+/// crash, before the cache is loaded from it. Only run on startup, as files being written
+/// look like leftovers.
+///
+/// Interrupted commits are finished or rolled back, staged files without a digest and
+/// digests or files missing their counterpart are removed. Partial downloads with their
+/// digest are kept to be resumed. With an `index`, cached files have no digest file.
+pub fn recover(root: &Path, index: bool) -> io::Result<()> {
+    if !root.is_dir() {
+        return Ok(());
+    }
+
+    recover_dir(root, index)?;
+    for &encoding in Encoding::ALL.iter() {
+        let dir = root.join(VARIANTS_DIR).join(encoding.as_str());
+        if dir.is_dir() {
+            recover_dir(&dir, index)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn recover_dir(dir: &Path, index: bool) -> io::Result<()> {
+    for name in file_names(dir)? {
+        if name.starts_with('.') && name.ends_with(STAGED_DIGEST_SUFFIX) {
+            let path = dir.join(&name);
+            match Digest::recover(&path) {
+                Ok(true) => log::info!("Finished commit of {}", path.to_string_lossy()),
+                Ok(false) => log::warn!("Rolled back commit of {}", path.to_string_lossy()),
+                Err(err) => return Err(io::Error::other(err)),
+            }
+        }
+    }
+
+    let names = file_names(dir)?;
+    for name in names.iter() {
+        let orphan = if let Some(file_name) = name.strip_suffix(".digest") {
+            !names.contains(file_name)
+        } else if name.ends_with(COMPRESS_SUFFIX) {
+            true
+        } else if name.ends_with(DOWNLOAD_SUFFIX) || (!index && !name.starts_with('.')) {
+            !names.contains(&format!("{}.digest", name))
+        } else {
+            false
+        };
+
+        if orphan {
+            let path = dir.join(name);
+            log::warn!("Removing leftover {}", path.to_string_lossy());
+            fs::remove_file(path)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn file_names(dir: &Path) -> io::Result<HashSet<String>> {
+    let mut res = HashSet::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if entry.file_type()?.is_file() {
+            res.insert(entry.file_name().to_string_lossy().into_owned());
+        }
+    }
+
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::test_dir::TestDir;
+
+    #[test]
+    fn test_recover() {
+        let dir = TestDir::new("recover");
+
+        let write = |name: &str, data: &str| fs::write(dir.join(name), data).unwrap();
+        let hash = |data: &str| blake3::hash(data.as_bytes());
+
+        // Committed before the crash
+        write("a", "a");
+        Digest::new(dir.join("a"), "text/plain", Vec::new(), hash("a"))
+            .write()
+            .unwrap();
+
+        // Crashed after renaming the file, before renaming the digest
+        write(".b.download", "b");
+        let mut digest = Digest::new(dir.join(".b.download"), "text/plain", Vec::new(), hash("b"));
+        digest.commit(dir.join("b")).unwrap();
+        fs::rename(dir.join("b.digest"), dir.join(".b.digest.tmp")).unwrap();
+
+        // Crashed before renaming the file
+        write(".c.download", "c");
+        let mut digest = Digest::new(dir.join(".c.download"), "text/plain", Vec::new(), hash("c"));
+        digest.commit(dir.join("c")).unwrap();
+        fs::rename(dir.join("c"), dir.join(".c.download")).unwrap();
+        fs::rename(dir.join("c.digest"), dir.join(".c.digest.tmp")).unwrap();
+
+        write("d", "without digest");
+        write("e.digest", "without file");
+        write(".f.compress", "staged");
+
+        recover(&dir, false).unwrap();
+
+        let mut names: Vec<_> = file_names(&dir).unwrap().into_iter().collect();
+        names.sort();
+        assert_eq!(names, vec!["a", "a.digest", "b", "b.digest"]);
+        Digest::for_path(dir.join("b")).unwrap().verify().unwrap();
+    }
+}
@@ -0,0 +1,208 @@
+use super::cache::Cache;
+use super::limits::NodeLimits;
+use super::{index, recovery};
+use crate::config::{CacheConfig, Config, ConfigError, Entry};
+use crate::metrics;
+use actix_web::web;
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::RwLock;
+
+/// All caches of the node by entry name, shared between the workers and updated when the
+/// config is reloaded
+pub struct Caches {
+    config: RwLock<Config>,
+    limits: RwLock<NodeLimits>,
+    caches: RwLock<HashMap<String, web::Data<Cache>>>,
+}
+
+impl Caches {
+    pub fn new(config: Config) -> Result<Self, ConfigError> {
+        let limits = NodeLimits::new(&config.cache);
+        let mut caches = HashMap::new();
+        for name in config.entries.keys() {
+            prepare(name, &config)?;
+            let cache = Cache::new(name, &config, &limits)?;
+            caches.insert(name.clone(), web::Data::new(cache));
+        }
+
+        Ok(Caches {
+            config: RwLock::new(config),
+            limits: RwLock::new(limits),
+            caches: RwLock::new(caches),
+        })
+    }
+
+    pub fn get(&self, name: &str) -> Option<web::Data<Cache>> {
+        self.caches.read().unwrap().get(name).cloned()
+    }
+
+    /// Returns all caches, sorted by entry name
+    pub fn all(&self) -> Vec<web::Data<Cache>> {
+        let mut res: Vec<_> = self.caches.read().unwrap().values().cloned().collect();
+        res.sort_by(|a, b| a.name.cmp(&b.name));
+        res
+    }
+
+    /// Applies a reloaded config. Caches of unchanged entries are kept and only take over
+    /// changed bandwidth limits of the node, changed entries are rebuilt keeping their loaded
+    /// files unless their directory or digest store changed, new entries are loaded from
+    /// disk and removed entries are dropped, their files stay in place.
+    pub async fn reload(&self, config: Config) {
+        let old = self.config.read().unwrap().clone();
+
+        if old.cache.bind != config.cache.bind
+            || old.cache.admin_token != config.cache.admin_token
+            || old.cache.access_log != config.cache.access_log
+            || old.cache.node_id != config.cache.node_id
+            || old.cache.node_id_header != config.cache.node_id_header
+            || old.cache.shutdown_timeout != config.cache.shutdown_timeout
+        {
+            log::warn!(
+                "Changes to bind, admin_token, access_log, node_id and shutdown_timeout \
+                 require a restart"
+            );
+        }
+
+        let rebuild_all = cache_settings_changed(&old.cache, &config.cache);
+        let bandwidth_changed = old.cache.bandwidth != config.cache.bandwidth;
+        let limits = if bandwidth_changed {
+            NodeLimits::new(&config.cache)
+        } else {
+            self.limits.read().unwrap().clone()
+        };
+        let mut caches = HashMap::new();
+
+        for (name, entry) in config.entries.iter() {
+            let existing = self.get(name);
+
+            match existing {
+                Some(cache) if !rebuild_all && !entry_changed(old.entries.get(name), entry) => {
+                    if bandwidth_changed {
+                        cache.set_limits(entry, &config.cache, &limits);
+                    }
+                    caches.insert(name.clone(), cache);
+                }
+                _ => {
+                    log::info!("Loading entry {}", name);
+
+                    let (name, config, limits) = (name.clone(), config.clone(), limits.clone());
+                    let load = move || match existing {
+                        Some(cache) if cache.keeps_files(&config) => {
+                            cache.rebuild(&config, &limits)
+                        }
+                        Some(cache) => {
+                            prepare(&name, &config)?;
+                            cache.rebuild(&config, &limits)
+                        }
+                        None => {
+                            prepare(&name, &config)?;
+                            Cache::new(&name, &config, &limits)
+                        }
+                    };
+                    match web::block(load).await {
+                        Ok(cache) => {
+                            cache.forget_unmatched().await;
+                            caches.insert(cache.name.clone(), web::Data::new(cache));
+                        }
+                        Err(err) => {
+                            log::error!("Failed to load entry, aborting the reload: {}", err);
+                            return;
+                        }
+                    }
+                }
+            }
+        }
+
+        for name in old.entries.keys() {
+            if !config.entries.contains_key(name) {
+                log::info!("Removing entry {}", name);
+                let _ = metrics::CACHED_FILES.remove_label_values(&[name]);
+                let _ = metrics::CACHE_SIZE.remove_label_values(&[name]);
+            }
+        }
+
+        *self.caches.write().unwrap() = caches;
+        *self.config.write().unwrap() = config;
+        *self.limits.write().unwrap() = limits;
+    }
+}
+
+/// Converts the digests of an entry to the configured store, recovers its directory and
+/// compacts its index before the cache is loaded from
+/// it. Only run for directories no cache is loaded from yet.
+fn prepare(name: &str, config: &Config) -> Result<(), ConfigError> {
+    let root = Path::new(&config.cache.root_path).join(name);
+    index::convert(&root, config.cache.index)?;
+    recovery::recover(&root, config.cache.index)?;
+    if config.cache.index {
+        index::compact(&root)?;
+    }
+    Ok(())
+}
+
+/// Whether the entry changed in a way its cache has to be rebuilt for, the limiters apply
+/// changed client limits on their own
+fn entry_changed(old: Option<&Entry>, new: &Entry) -> bool {
+    let without_limits = |entry: &Entry| Entry {
+        client_limits: Default::default(),
+        ..entry.clone()
+    };
+    old.map(without_limits) != Some(without_limits(new))
+}
+
+/// Whether settings shared by all caches changed, requiring all of them to be rebuilt
+fn cache_settings_changed(old: &CacheConfig, new: &CacheConfig) -> bool {
+    old.root_path != new.root_path
+        || old.max_ranges != new.max_ranges
+        || old.head_fill != new.head_fill
+        || old.parents != new.parents
+        || old.peers != new.peers
+        || old.node_url != new.node_url
+        || old.bind != new.bind
+        || old.index != new.index
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::digest::Digest;
+    use crate::util::test_dir::TestDir;
+    use std::fs;
+
+    fn config(root: &Path, entries: &[&str]) -> Config {
+        let mut toml = format!(
+            "[cache]\nroot_path = {:?}\n\n[proxy]\nnodes = []\n",
+            root.to_string_lossy()
+        );
+        for name in entries {
+            toml += &format!("\n[entries.{}]\nbase_url = \"http://127.0.0.1:1/\"\n", name);
+        }
+        toml::from_str(&toml).unwrap()
+    }
+
+    #[test]
+    fn test_reload_recovers_new_entry() {
+        let root = TestDir::new("reload");
+        let caches = Caches::new(config(&root, &["a"])).unwrap();
+
+        // Crashed after renaming the file, before renaming the digest
+        let dir = root.join("b");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join(".x.download"), "x").unwrap();
+        let hash = blake3::hash(b"x");
+        let mut digest = Digest::new(dir.join(".x.download"), "text/plain", Vec::new(), hash);
+        digest.commit(dir.join("x")).unwrap();
+        fs::rename(dir.join("x.digest"), dir.join(".x.digest.tmp")).unwrap();
+
+        let config = config(&root, &["a", "b"]);
+        let caches = actix_rt::System::new("test").block_on(async move {
+            caches.reload(config).await;
+            caches
+        });
+
+        assert!(caches.get("b").is_some());
+        assert!(!dir.join(".x.digest.tmp").exists());
+        Digest::for_path(dir.join("x")).unwrap().verify().unwrap();
+    }
+}
@@ -0,0 +1,72 @@
+use actix_rt::signal::ctrl_c;
+use actix_rt::signal::unix::{signal, SignalKind};
+use futures_util::future::{self, BoxFuture, FutureExt, Shared};
+use lazy_static::lazy_static;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::sync::oneshot;
+
+lazy_static! {
+    /// Shutdown state of the cache node
+    pub static ref SHUTDOWN: Shutdown = Shutdown::new();
+}
+
+/// Resolves once running downloads have to stop and save their progress
+pub type Deadline = Shared<BoxFuture<'static, ()>>;
+
+/// Tracks a graceful shutdown. Once it began, no new downloads are started and running ones
+/// get until the deadline to finish before they are checkpointed.
+pub struct Shutdown {
+    stopping: AtomicBool,
+    sender: Mutex<Option<oneshot::Sender<()>>>,
+    deadline: Deadline,
+}
+
+impl Shutdown {
+    fn new() -> Self {
+        let (sender, receiver) = oneshot::channel();
+
+        Shutdown {
+            stopping: AtomicBool::new(false),
+            sender: Mutex::new(Some(sender)),
+            deadline: receiver.map(|_| ()).boxed().shared(),
+        }
+    }
+
+    pub fn is_stopping(&self) -> bool {
+        self.stopping.load(Ordering::SeqCst)
+    }
+
+    pub fn deadline(&self) -> Deadline {
+        self.deadline.clone()
+    }
+
+    /// Stops new downloads and lets the deadline pass after `timeout`
+    pub fn begin(&'static self, timeout: Duration) {
+        if self.stopping.swap(true, Ordering::SeqCst) {
+            return;
+        }
+
+        actix_rt::spawn(async move {
+            actix_rt::time::delay_for(timeout).await;
+            if let Some(sender) = self.sender.lock().unwrap().take() {
+                let _ = sender.send(());
+            }
+        });
+    }
+}
+
+/// Resolves on SIGTERM or Ctrl-C
+pub async fn signaled() {
+    let mut terminate = match signal(SignalKind::terminate()) {
+        Ok(terminate) => terminate,
+        Err(err) => {
+            log::error!("Failed to listen for SIGTERM: {}", err);
+            let _ = ctrl_c().await;
+            return;
+        }
+    };
+
+    future::select(Box::pin(terminate.recv()), Box::pin(ctrl_c())).await;
+}
@@ -0,0 +1,240 @@
+use super::cache::{Cache, CacheResult};
+use super::Caches;
+use crate::config::Config;
+use actix_web::web::{self, Bytes};
+use actix_web::HttpResponse;
+use futures_util::stream::{self, StreamExt};
+use globset::Glob;
+use serde::{Deserialize, Serialize};
+use std::io;
+
+/// Upper bound for the number of files warmed at once, whatever the request asks for
+const MAX_PARALLEL: usize = 16;
+
+#[derive(Deserialize, Serialize)]
+pub struct WarmRequest {
+    #[serde(default)]
+    pub files: Vec<String>,
+    /// Glob that is matched against the files linked in the origin's directory listing
+    pub glob: Option<String>,
+    #[serde(default = "default_parallel")]
+    pub parallel: usize,
+}
+
+/// Result for a single file, sent as one JSON line each
+#[derive(Deserialize, Serialize)]
+pub struct WarmProgress {
+    pub file: String,
+    pub status: WarmStatus,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum WarmStatus {
+    Hit,
+    Filled,
+    NotFound,
+    Failed,
+}
+
+pub async fn warm(
+    entry: web::Path<String>,
+    request: web::Json<WarmRequest>,
+    caches: web::Data<Caches>,
+) -> HttpResponse {
+    let cache = match caches.get(entry.as_str()) {
+        Some(cache) => cache,
+        None => return HttpResponse::NotFound().body("Unknown entry"),
+    };
+
+    let request = request.into_inner();
+    let mut files = request.files;
+
+    if let Some(glob) = request.glob {
+        let glob = match Glob::new(&glob) {
+            Ok(glob) => glob.compile_matcher(),
+            Err(err) => return HttpResponse::BadRequest().body(err.to_string()),
+        };
+
+        match cache.list_origin().await {
+            Ok(listed) => files.extend(listed.into_iter().filter(|f| glob.is_match(f))),
+            Err(err) => {
+                log::error!("Failed to list origin of {}: {:?}", cache.name, err);
+                return HttpResponse::BadGateway().body("Failed to list origin");
+            }
+        }
+    }
+
+    log::info!("Warming {} files of {}", files.len(), cache.name);
+
+    let progress = stream::iter(files)
+        .map(move |file| {
+            let cache = cache.clone();
+            async move { warm_file(&cache, file).await }
+        })
+        .buffer_unordered(request.parallel.clamp(1, MAX_PARALLEL))
+        .map(|progress| {
+            let mut line = serde_json::to_vec(&progress).unwrap();
+            line.push(b'\n');
+            Ok::<_, actix_web::Error>(Bytes::from(line))
+        });
+
+    HttpResponse::Ok()
+        .content_type("application/x-ndjson")
+        .streaming(progress)
+}
+
+async fn warm_file(cache: &Cache, file: String) -> WarmProgress {
+    if cache.lookup(&file).await.is_some() {
+        return WarmProgress {
+            file,
+            status: WarmStatus::Hit,
+            error: None,
+        };
+    }
+
+    let (status, error) = match cache.fill(&file).await {
+        CacheResult::Ok(..) => (WarmStatus::Filled, None),
+        CacheResult::NotFound => (WarmStatus::NotFound, None),
+        CacheResult::DownloadError(err) => (WarmStatus::Failed, Some(err.to_string())),
+    };
+
+    WarmProgress {
+        file,
+        status,
+        error,
+    }
+}
+
+/// Asks a running cache node to download the given files and prints its progress
+pub fn run(config: Config, matches: &clap::ArgMatches<'_>) -> io::Result<()> {
+    let entry = matches.value_of("entry").unwrap();
+    let node = matches
+        .value_of("node")
+        .map(|n| n.to_owned())
+        .unwrap_or_else(|| format!("http://{}", config.cache.bind));
+    let token = config
+        .cache
+        .admin_token
+        .ok_or_else(|| io::Error::other("No admin_token configured"))?;
+
+    let request = WarmRequest {
+        files: matches
+            .values_of("files")
+            .map(|v| v.map(|f| f.to_owned()).collect())
+            .unwrap_or_default(),
+        glob: matches.value_of("glob").map(|g| g.to_owned()),
+        parallel: matches
+            .value_of("parallel")
+            .unwrap()
+            .parse()
+            .map_err(io_error)?,
+    };
+
+    let url = format!("{}/admin/v1/{}/warm", node.trim_end_matches('/'), entry);
+    request_warm(url, token, request)
+}
+
+#[actix_rt::main]
+async fn request_warm(url: String, token: String, request: WarmRequest) -> io::Result<()> {
+    let resp = reqwest::Client::new()
+        .post(&url)
+        .bearer_auth(token)
+        .json(&request)
+        .send()
+        .await
+        .map_err(io_error)?;
+
+    if !resp.status().is_success() {
+        let status = resp.status();
+        let body = resp.text().await.unwrap_or_default();
+        return Err(io_error(format!("{}: {}", status, body)));
+    }
+
+    let mut body = resp.bytes_stream();
+    let mut buffer = Vec::new();
+    let mut total = 0;
+    let mut failed = 0;
+
+    while let Some(chunk) = body.next().await {
+        buffer.extend_from_slice(&chunk.map_err(io_error)?);
+
+        while let Some(pos) = buffer.iter().position(|b| *b == b'\n') {
+            let line: Vec<u8> = buffer.drain(..=pos).collect();
+            let progress: WarmProgress = serde_json::from_slice(&line)?;
+
+            total += 1;
+            match progress.status {
+                WarmStatus::Hit | WarmStatus::Filled => {}
+                _ => failed += 1,
+            }
+
+            match progress.error {
+                Some(error) => println!("{:?}\t{}\t{}", progress.status, progress.file, error),
+                None => println!("{:?}\t{}", progress.status, progress.file),
+            }
+        }
+    }
+
+    println!("Warmed {} of {} files", total - failed, total);
+
+    if failed > 0 {
+        Err(io_error(format!("{} files failed", failed)))
+    } else {
+        Ok(())
+    }
+}
+
+fn io_error<E: ToString>(err: E) -> io::Error {
+    io::Error::other(err.to_string())
+}
+
+fn default_parallel() -> usize {
+    2
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache_server::limits::NodeLimits;
+    use crate::metrics;
+    use crate::util::test_dir::TestDir;
+    use crate::util::test_server;
+
+    #[test]
+    fn test_warm_leaves_stats() {
+        let port = test_server::respond_once(
+            b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\nConnection: close\r\n\r\nhello",
+        );
+
+        let root = TestDir::new("warm");
+        let config: Config = toml::from_str(&format!(
+            r#"
+[cache]
+root_path = {:?}
+
+[proxy]
+nodes = []
+
+[entries.warm]
+base_url = "http://127.0.0.1:{}/"
+"#,
+            root.to_string_lossy(),
+            port
+        ))
+        .unwrap();
+        let cache = web::Data::new(Cache::new("warm", &config, &NodeLimits::default()).unwrap());
+
+        let warmed = cache.clone();
+        let progress = actix_rt::System::new("test")
+            .block_on(async move { warm_file(&warmed, "a.txt".to_owned()).await });
+        assert_eq!(progress.status, WarmStatus::Filled);
+        assert_eq!(cache.access_stats("a.txt").requests, 0);
+        for result in &["hit", "miss"] {
+            let requests = metrics::CACHE_REQUESTS.with_label_values(&["warm", result]);
+            assert_eq!(requests.get(), 0);
+        }
+    }
+}
@@ -0,0 +1,302 @@
+use super::Config;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use toml::Value;
+
+/// Prefix of the environment variables overriding config values
+const ENV_PREFIX: &str = "BCDN_";
+
+/// Where a config value was set
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Source {
+    Default,
+    File,
+    Env(String),
+    Flag(&'static str),
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Source::Default => write!(f, "default"),
+            Source::File => write!(f, "file"),
+            Source::Env(var) => write!(f, "env {}", var),
+            Source::Flag(flag) => write!(f, "flag --{}", flag),
+        }
+    }
+}
+
+/// Loads the config from the file, then applies the `BCDN_*` environment variables and
+/// finally the command line flags.
+///
+/// Environment variables name the dotted key in upper case with `__` separating the levels,
+/// e.g. `BCDN_ENTRIES__NUKULAR__BASE_URL`. Without `__` the first `_` separates the section
+/// from the key, as in `BCDN_CACHE_ROOT_PATH`. Values are parsed as TOML if the key does not
+/// hold a string, so `BCDN_PROXY_NODES='["http://a/", "http://b/"]'` sets a list.
+#[derive(Clone, Debug)]
+pub struct Loader {
+    path: PathBuf,
+    flags: Vec<(String, Value, &'static str)>,
+}
+
+impl Loader {
+    pub fn new<P: AsRef<Path>>(path: P) -> Self {
+        Loader {
+            path: path.as_ref().to_owned(),
+            flags: Vec::new(),
+        }
+    }
+
+    /// Overrides the value at the dotted `key` as given by the command line flag `flag`
+    pub fn with_flag(mut self, key: &str, value: Value, flag: &'static str) -> Self {
+        self.flags.push((key.to_owned(), value, flag));
+        self
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn load(&self) -> io::Result<Layered> {
+        let text = fs::read_to_string(&self.path)?;
+        let value: Value = toml::from_str(&text).map_err(invalid_data)?;
+
+        let mut layered = Layered {
+            value,
+            sources: BTreeMap::new(),
+            text,
+        };
+        collect_sources(&layered.value, "", &mut layered.sources);
+
+        let mut vars: Vec<(String, String)> = std::env::vars()
+            .filter(|(name, _)| name.starts_with(ENV_PREFIX))
+            .collect();
+        vars.sort();
+
+        for (name, raw) in vars {
+            let key = env_key(&name[ENV_PREFIX.len()..]);
+            let value = match layered.get(&key) {
+                Some(Value::String(_)) => Value::String(raw),
+                _ => parse_value(&raw),
+            };
+            layered.set(&key, value, Source::Env(name));
+        }
+
+        for (key, value, flag) in self.flags.iter() {
+            layered.set(key, value.clone(), Source::Flag(flag));
+        }
+
+        Ok(layered)
+    }
+}
+
+/// The merged config values together with the source of each of them
+pub struct Layered {
+    value: Value,
+    sources: BTreeMap<String, Source>,
+    /// Contents of the config file
+    pub text: String,
+}
+
+impl Layered {
+    pub fn config(&self) -> io::Result<Config> {
+        self.value.clone().try_into().map_err(invalid_data)
+    }
+
+    /// Returns where the value at the dotted `key` was set, list indices are ignored
+    pub fn source_of(&self, key: &str) -> Source {
+        let key = key.split('[').next().unwrap_or(key);
+
+        self.sources
+            .iter()
+            .filter(|(k, _)| key == *k || key.starts_with(&format!("{}.", k)))
+            .max_by_key(|(k, _)| k.len())
+            .map_or(Source::Default, |(_, source)| source.clone())
+    }
+
+    fn get(&self, key: &str) -> Option<&Value> {
+        key.split('.')
+            .try_fold(&self.value, |value, part| value.as_table()?.get(part))
+    }
+
+    fn set(&mut self, key: &str, value: Value, source: Source) {
+        let parts: Vec<&str> = key.split('.').collect();
+        let mut table = &mut self.value;
+
+        for part in parts[..parts.len() - 1].iter() {
+            if !table.is_table() {
+                *table = Value::Table(Default::default());
+            }
+            table = table
+                .as_table_mut()
+                .unwrap()
+                .entry(part.to_string())
+                .or_insert_with(|| Value::Table(Default::default()));
+        }
+
+        if !table.is_table() {
+            *table = Value::Table(Default::default());
+        }
+        table
+            .as_table_mut()
+            .unwrap()
+            .insert(parts[parts.len() - 1].to_owned(), value);
+
+        let prefix = format!("{}.", key);
+        self.sources.retain(|k, _| !k.starts_with(&prefix));
+        self.sources.insert(key.to_owned(), source);
+    }
+}
+
+fn collect_sources(value: &Value, prefix: &str, sources: &mut BTreeMap<String, Source>) {
+    match value.as_table() {
+        Some(table) => {
+            for (key, value) in table {
+                let key = if prefix.is_empty() {
+                    key.clone()
+                } else {
+                    format!("{}.{}", prefix, key)
+                };
+                collect_sources(value, &key, sources);
+            }
+        }
+        None => {
+            sources.insert(prefix.to_owned(), Source::File);
+        }
+    }
+}
+
+/// Turns the name of an environment variable without prefix into a dotted key
+fn env_key(name: &str) -> String {
+    let name = name.to_ascii_lowercase();
+
+    if name.contains("__") {
+        name.split("__").collect::<Vec<_>>().join(".")
+    } else {
+        name.replacen('_', ".", 1)
+    }
+}
+
+/// Parses a raw value as TOML, falling back to a plain string
+fn parse_value(raw: &str) -> Value {
+    toml::from_str::<BTreeMap<String, Value>>(&format!("v = {}", raw))
+        .ok()
+        .and_then(|mut table| table.remove("v"))
+        .unwrap_or_else(|| Value::String(raw.to_owned()))
+}
+
+/// Flattens `value` into dotted keys and their TOML representation
+pub fn flatten(value: &Value, prefix: &str, res: &mut Vec<(String, String)>) {
+    match value.as_table() {
+        Some(table) => {
+            for (key, value) in table {
+                let key = if prefix.is_empty() {
+                    key.clone()
+                } else {
+                    format!("{}.{}", prefix, key)
+                };
+                flatten(value, &key, res);
+            }
+        }
+        None => res.push((prefix.to_owned(), value.to_string())),
+    }
+}
+
+/// Whether the value at the dotted `key` holds credentials that are not to be printed.
+/// Everything below `origin.headers` and `origin.auth` of an entry counts as such, as
+/// origins often expect keys in custom headers.
+pub fn is_secret(key: &str) -> bool {
+    let parts: Vec<String> = key
+        .split('.')
+        .map(|part| part.to_ascii_lowercase())
+        .collect();
+    let last = parts.last().map_or("", |part| part.as_str());
+
+    last.ends_with("token")
+        || last.ends_with("password")
+        || last == "authorization"
+        || parts
+            .windows(2)
+            .rev()
+            .skip(1)
+            .any(|pair| pair[0] == "origin" && (pair[1] == "headers" || pair[1] == "auth"))
+}
+
+fn invalid_data<E>(err: E) -> io::Error
+where
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    io::Error::new(io::ErrorKind::InvalidData, err)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_secret() {
+        assert!(is_secret("cache.admin_token"));
+        assert!(is_secret("entries.a.origin.auth.password"));
+        assert!(is_secret("entries.a.origin.auth.type"));
+        assert!(is_secret("entries.a.origin.headers.X-Origin-Key"));
+        assert!(!is_secret("entries.a.origin.headers"));
+        assert!(!is_secret("entries.a.origin.user_agent"));
+        assert!(!is_secret("entries.a.headers.add.X-Origin-Key"));
+    }
+
+    #[test]
+    fn test_env_key() {
+        assert_eq!(env_key("CACHE_BIND"), "cache.bind");
+        assert_eq!(env_key("CACHE_ROOT_PATH"), "cache.root_path");
+        assert_eq!(
+            env_key("ENTRIES__NUKULAR__BASE_URL"),
+            "entries.nukular.base_url"
+        );
+    }
+
+    #[test]
+    fn test_layers() {
+        let mut layered = Layered {
+            value: toml::from_str(
+                r#"
+[cache]
+root_path = "./cache"
+
+[entries.a]
+base_url = "http://a/"
+patterns = ["*.m4a"]
+"#,
+            )
+            .unwrap(),
+            sources: BTreeMap::new(),
+            text: String::new(),
+        };
+        collect_sources(&layered.value, "", &mut layered.sources);
+
+        layered.set(
+            "entries.a.patterns",
+            parse_value(r#"["*.mp3"]"#),
+            Source::Env("BCDN_ENTRIES__A__PATTERNS".to_owned()),
+        );
+        layered.set(
+            "proxy.nodes",
+            Value::Array(vec![Value::String("http://node/".to_owned())]),
+            Source::Flag("node"),
+        );
+
+        let config = layered.config().unwrap();
+        assert_eq!(config.entries["a"].patterns, vec!["*.mp3"]);
+        assert_eq!(config.proxy.nodes, vec!["http://node/"]);
+
+        assert_eq!(layered.source_of("cache.root_path"), Source::File);
+        assert_eq!(
+            layered.source_of("entries.a.patterns[0]"),
+            Source::Env("BCDN_ENTRIES__A__PATTERNS".to_owned())
+        );
+        assert_eq!(layered.source_of("proxy.nodes"), Source::Flag("node"));
+        assert_eq!(layered.source_of("cache.bind"), Source::Default);
+    }
+}
@@ -0,0 +1,528 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::future::Future;
+use std::io;
+use thiserror::Error;
+
+mod layers;
+mod validate;
+pub use layers::{flatten, is_secret, Layered, Loader, Source};
+pub use validate::{validate, Role};
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct Config {
+    #[serde(default)]
+    pub cache: CacheConfig,
+    #[serde(default)]
+    pub proxy: ProxyConfig,
+    pub entries: HashMap<String, Entry>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
+pub struct CacheConfig {
+    #[serde(default = "default_cache_bind")]
+    pub bind: String,
+    pub root_path: String,
+    /// Maximum number of distinct ranges served in one `multipart/byteranges` response
+    #[serde(default = "default_max_ranges")]
+    pub max_ranges: usize,
+    /// Whether a `HEAD` request for a file that is not cached yet downloads it, otherwise
+    /// the request is forwarded to the origin
+    #[serde(default)]
+    pub head_fill: bool,
+    /// Bearer token for the admin API, which is disabled if no token is set
+    pub admin_token: Option<String>,
+    pub access_log: Option<AccessLogConfig>,
+    /// Cache nodes to fill misses from before going to the origin, tried in order
+    #[serde(default)]
+    pub parents: Vec<String>,
+    /// Identity of this node, sent in the `node_id_header` of every response if set
+    pub node_id: Option<String>,
+    #[serde(default = "default_node_id_header")]
+    pub node_id_header: String,
+    /// Sibling cache nodes asked for files they already hold before filling a miss from
+    /// the parents or the origin, usually the nodes of the proxy. This node is skipped.
+    #[serde(default)]
+    pub peers: Vec<String>,
+    /// URL the other nodes reach this node at, used to skip it in `peers`. Without it the
+    /// peers with the bind address are skipped, host names are not resolved, which misses
+    /// names and public addresses of this node.
+    pub node_url: Option<String>,
+    /// Seconds running downloads get to finish on shutdown before their progress is saved
+    /// to resume them after the next start
+    #[serde(default = "default_shutdown_timeout")]
+    pub shutdown_timeout: u64,
+    /// Keep the digests of each entry in one append-only index instead of a `.digest` file
+    /// next to every cached file, existing caches are converted when the entry is loaded
+    #[serde(default)]
+    pub index: bool,
+    #[serde(default)]
+    pub bandwidth: BandwidthConfig,
+    #[serde(default)]
+    pub client_limits: ClientLimitsConfig,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
+pub struct ProxyConfig {
+    #[serde(default = "default_proxy_bind")]
+    pub bind: String,
+    pub nodes: Vec<String>,
+    /// Bearer token for the admin API, also used for requests to the nodes' admin API
+    pub admin_token: Option<String>,
+    pub access_log: Option<AccessLogConfig>,
+    #[serde(default)]
+    pub client_limits: ClientLimitsConfig,
+}
+
+/// Access log of a server.
+///
+/// The log file at `path` is rotated to `path.1`, `path.2`, … once it grows beyond
+/// `max_size` bytes, keeping at most `max_files` rotated files. The `common` and `combined`
+/// formats are followed by the entry, the cache outcome, the requested range, the node
+/// chosen by the proxy and the latency in milliseconds.
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct AccessLogConfig {
+    pub path: String,
+    #[serde(default)]
+    pub format: LogFormat,
+    #[serde(default = "default_log_max_size")]
+    pub max_size: u64,
+    #[serde(default = "default_log_max_files")]
+    pub max_files: usize,
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum LogFormat {
+    Common,
+    #[default]
+    Combined,
+    Json,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct Entry {
+    pub base_url: String,
+    #[serde(default = "default_patterns")]
+    pub patterns: Vec<String>,
+    #[serde(default)]
+    pub headers: HeaderConfig,
+    #[serde(default)]
+    pub compression: CompressionConfig,
+    #[serde(default)]
+    pub origin: OriginConfig,
+    #[serde(default)]
+    pub negative_cache: NegativeCacheConfig,
+    #[serde(default)]
+    pub bandwidth: BandwidthConfig,
+    #[serde(default)]
+    pub client_limits: ClientLimitsConfig,
+}
+
+/// Rules for the response headers of an entry.
+///
+/// Origin headers matching one of the `passthrough` globs are stored in the digest when a
+/// file is downloaded. When serving, stored headers matching `strip` are dropped and the
+/// headers in `add` are set.
+#[derive(Deserialize, Serialize, Clone, Debug, Default, PartialEq)]
+pub struct HeaderConfig {
+    #[serde(default)]
+    pub passthrough: Vec<String>,
+    #[serde(default)]
+    pub strip: Vec<String>,
+    #[serde(default)]
+    pub add: BTreeMap<String, String>,
+}
+
+/// Compression settings of an entry.
+///
+/// For every encoding in `encodings` a compressed variant is stored next to each downloaded
+/// file. If `on_the_fly` is set, files without a matching variant are compressed while
+/// serving. Files with a content type matching `skip_types` or smaller than `min_size` are
+/// never compressed.
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct CompressionConfig {
+    #[serde(default)]
+    pub encodings: Vec<Encoding>,
+    #[serde(default)]
+    pub on_the_fly: bool,
+    #[serde(default = "default_skip_types")]
+    pub skip_types: Vec<String>,
+    #[serde(default = "default_min_size")]
+    pub min_size: u64,
+}
+
+impl Default for CompressionConfig {
+    fn default() -> Self {
+        CompressionConfig {
+            encodings: Vec::new(),
+            on_the_fly: false,
+            skip_types: default_skip_types(),
+            min_size: default_min_size(),
+        }
+    }
+}
+
+/// How long origin failures for a file are remembered, in seconds. `not_found_ttl` applies
+/// to `404 Not Found` and `410 Gone`, `error_ttl` to server errors, 0 disables caching them.
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct NegativeCacheConfig {
+    #[serde(default = "default_not_found_ttl")]
+    pub not_found_ttl: u64,
+    #[serde(default = "default_error_ttl")]
+    pub error_ttl: u64,
+}
+
+impl Default for NegativeCacheConfig {
+    fn default() -> Self {
+        NegativeCacheConfig {
+            not_found_ttl: default_not_found_ttl(),
+            error_ttl: default_error_ttl(),
+        }
+    }
+}
+
+/// Bandwidth limits in bytes per second, unset values are unlimited.
+///
+/// In `cache`, `fill` and `serve` limit all entries together and `connection` every
+/// response. In an entry, `fill` and `serve` limit the entry and `connection` overrides the
+/// limit of its responses. `fill` covers downloads from the origin, parents and peers.
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct BandwidthConfig {
+    pub fill: Option<u64>,
+    pub serve: Option<u64>,
+    pub connection: Option<u64>,
+}
+
+/// Limits on the requests of each client IP address, unset values are unlimited.
+///
+/// `rate` is the number of requests per second, of which up to `burst` may come at once
+/// (`rate` by default). `connections` caps the requests in flight, including responses
+/// still being sent. Requests exceeding a limit are answered with `429 Too Many Requests`.
+///
+/// In an entry, the limits apply to the requests for the entry in addition to the server's.
+/// Clients in one of the `trusted` networks, given in CIDR notation, are exempt. Networks
+/// trusted by the server are exempt from the limits of its entries as well.
+#[derive(Deserialize, Serialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct ClientLimitsConfig {
+    pub rate: Option<u32>,
+    pub burst: Option<u32>,
+    pub connections: Option<u32>,
+    #[serde(default)]
+    pub trusted: Vec<String>,
+}
+
+/// Settings of the HTTP client used for the requests to the origin of an entry.
+///
+/// Timeouts are given in seconds, `timeout` covers the whole request including the body.
+/// `ca_bundle` is a PEM file with additional trusted certificates, `client_cert` a PKCS#12
+/// file with the certificate and key presented to the origin.
+///
+/// The `base_url` of the entry and the `mirrors` are tried in the order given by `strategy`,
+/// with `weight` being the weight of the `base_url`. An origin failing `max_failures` times
+/// in a row is marked down for `down_time` seconds and only tried if all others fail.
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct OriginConfig {
+    #[serde(default)]
+    pub mirrors: Vec<Mirror>,
+    #[serde(default)]
+    pub strategy: OriginStrategy,
+    #[serde(default = "default_weight")]
+    pub weight: u32,
+    #[serde(default = "default_max_failures")]
+    pub max_failures: u32,
+    #[serde(default = "default_down_time")]
+    pub down_time: u64,
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+    pub auth: Option<OriginAuth>,
+    pub user_agent: Option<String>,
+    pub connect_timeout: Option<u64>,
+    pub timeout: Option<u64>,
+    pub proxy: Option<String>,
+    pub ca_bundle: Option<String>,
+    pub client_cert: Option<String>,
+    #[serde(default)]
+    pub client_cert_password: String,
+    #[serde(default = "default_tls_verify")]
+    pub tls_verify: bool,
+    /// Whether a `403 Forbidden` of the origin is passed on to clients instead of being
+    /// answered with `502 Bad Gateway`
+    #[serde(default)]
+    pub forward_forbidden: bool,
+}
+
+impl Default for OriginConfig {
+    fn default() -> Self {
+        OriginConfig {
+            mirrors: Vec::new(),
+            strategy: OriginStrategy::default(),
+            weight: default_weight(),
+            max_failures: default_max_failures(),
+            down_time: default_down_time(),
+            headers: BTreeMap::new(),
+            auth: None,
+            user_agent: None,
+            connect_timeout: None,
+            timeout: None,
+            proxy: None,
+            ca_bundle: None,
+            client_cert: None,
+            client_cert_password: String::new(),
+            tls_verify: default_tls_verify(),
+            forward_forbidden: false,
+        }
+    }
+}
+
+/// Additional origin serving the same files as the `base_url` of an entry
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct Mirror {
+    pub url: String,
+    #[serde(default = "default_weight")]
+    pub weight: u32,
+}
+
+/// Order in which the origins of an entry are tried
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum OriginStrategy {
+    /// The `base_url` first, the mirrors in the given order as backups
+    #[default]
+    Failover,
+    /// Each request starts with the next origin
+    RoundRobin,
+    /// Each request starts with a random origin, chosen proportional to the weights
+    Weighted,
+}
+
+/// Credentials sent to the origin, e.g. `{ type = "bearer", token = "…" }` or
+/// `{ type = "basic", username = "…", password = "…" }`
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum OriginAuth {
+    Basic {
+        username: String,
+        #[serde(default)]
+        password: String,
+    },
+    Bearer {
+        token: String,
+    },
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Encoding {
+    #[serde(rename = "gzip")]
+    Gzip,
+    #[serde(rename = "br")]
+    Brotli,
+    #[serde(rename = "zstd")]
+    Zstd,
+}
+
+impl Encoding {
+    pub const ALL: [Encoding; 3] = [Encoding::Gzip, Encoding::Brotli, Encoding::Zstd];
+
+    /// Name of the encoding as used in `Accept-Encoding` and `Content-Encoding`
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Encoding::Gzip => "gzip",
+            Encoding::Brotli => "br",
+            Encoding::Zstd => "zstd",
+        }
+    }
+}
+
+/// Validates the parts of the config used by `roles` and returns the problems found as
+/// messages, pointing to the line in the config file or the overriding variable or flag
+pub fn problems(config: &Config, layered: &Layered, roles: &[Role]) -> Vec<String> {
+    validate(config, Some(&layered.text), roles)
+        .into_iter()
+        .map(|mut problem| match layered.source_of(&problem.path) {
+            Source::File | Source::Default => problem.to_string(),
+            source => {
+                problem.line = None;
+                format!("{} (set by {})", problem, source)
+            }
+        })
+        .collect()
+}
+
+/// Validates the parts of the config used by `roles`, logging all problems found
+pub fn check(config: &Config, layered: &Layered, roles: &[Role]) -> io::Result<()> {
+    let problems = problems(config, layered, roles);
+
+    for problem in problems.iter() {
+        log::error!("{}", problem);
+    }
+
+    if problems.is_empty() {
+        Ok(())
+    } else {
+        Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Found {} problems in the config", problems.len()),
+        ))
+    }
+}
+
+/// Reloads the config whenever the process receives `SIGHUP` and passes the new config to
+/// `apply`. A config that fails to load or to validate for `roles` is logged and ignored.
+pub fn reload_on_hangup<F, Fut>(loader: Loader, roles: &'static [Role], apply: F)
+where
+    F: Fn(Config) -> Fut + 'static,
+    Fut: Future<Output = ()>,
+{
+    use actix_rt::signal::unix::{signal, SignalKind};
+
+    actix_rt::spawn(async move {
+        let mut hangup = match signal(SignalKind::hangup()) {
+            Ok(hangup) => hangup,
+            Err(err) => {
+                log::error!(
+                    "Failed to listen for SIGHUP, reloading is disabled: {}",
+                    err
+                );
+                return;
+            }
+        };
+
+        while hangup.recv().await.is_some() {
+            log::info!("Reloading config from {}", loader.path().to_string_lossy());
+
+            let config = loader.load().and_then(|layered| {
+                let config = layered.config()?;
+                check(&config, &layered, roles).map(|_| config)
+            });
+
+            match config {
+                Ok(config) => apply(config).await,
+                Err(err) => log::error!("Failed to reload config, keeping the old one: {}", err),
+            }
+        }
+    });
+}
+
+use globset::{Error, Glob, GlobSet, GlobSetBuilder};
+impl Entry {
+    pub fn get_globset(&self) -> Result<GlobSet, Error> {
+        let mut builder = GlobSetBuilder::new();
+
+        for pattern in self.patterns.iter() {
+            builder.add(Glob::new(pattern)?);
+        }
+
+        builder.build()
+    }
+}
+
+/// Error building a server component from the config, usually prevented by `validate`
+#[derive(Error, Debug)]
+pub enum ConfigError {
+    #[error("Unknown entry {0}")]
+    UnknownEntry(String),
+
+    #[error(transparent)]
+    UrlError(#[from] url::ParseError),
+
+    #[error(transparent)]
+    GlobError(#[from] globset::Error),
+
+    #[error("Invalid header {0}")]
+    InvalidHeader(String),
+
+    #[error("Header {0} is set by the cache node")]
+    ReservedHeader(String),
+
+    #[error(transparent)]
+    ClientError(#[from] reqwest::Error),
+
+    #[error(transparent)]
+    IoError(#[from] io::Error),
+}
+
+fn default_proxy_bind() -> String {
+    "127.0.0.1:1336".to_owned()
+}
+
+fn default_cache_bind() -> String {
+    "127.0.0.1:1337".to_owned()
+}
+
+fn default_max_ranges() -> usize {
+    crate::util::named_file::DEFAULT_MAX_RANGES
+}
+
+fn default_tls_verify() -> bool {
+    true
+}
+
+fn default_node_id_header() -> String {
+    "x-cache-node".to_owned()
+}
+
+fn default_shutdown_timeout() -> u64 {
+    30
+}
+
+fn default_not_found_ttl() -> u64 {
+    60
+}
+
+fn default_error_ttl() -> u64 {
+    5
+}
+
+fn default_weight() -> u32 {
+    1
+}
+
+fn default_max_failures() -> u32 {
+    3
+}
+
+fn default_down_time() -> u64 {
+    30
+}
+
+fn default_log_max_size() -> u64 {
+    100 * 1024 * 1024
+}
+
+fn default_log_max_files() -> usize {
+    5
+}
+
+fn default_skip_types() -> Vec<String> {
+    [
+        "audio/*",
+        "video/*",
+        "image/png",
+        "image/jpeg",
+        "image/gif",
+        "image/webp",
+        "image/avif",
+        "font/woff",
+        "font/woff2",
+        "application/zip",
+        "application/gzip",
+        "application/x-gzip",
+        "application/x-bzip2",
+        "application/x-xz",
+        "application/zstd",
+        "application/x-7z-compressed",
+        "application/vnd.rar",
+    ]
+    .iter()
+    .map(|s| (*s).to_owned())
+    .collect()
+}
+
+fn default_min_size() -> u64 {
+    1024
+}
+
+fn default_patterns() -> Vec<String> {
+    vec!["*".to_owned()]
+}
@@ -0,0 +1,431 @@
+use super::{BandwidthConfig, ClientLimitsConfig, Config, Entry, OriginStrategy};
+use crate::util::client_limits::Cidr;
+use actix_web::http::header::{HeaderName, HeaderValue};
+use globset::Glob;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use url::Url;
+
+/// Server whose part of the config is checked
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Role {
+    Cache,
+    Proxy,
+}
+
+/// A problem found in the config, `path` is the dotted key of the offending value
+#[derive(Debug, PartialEq, Eq)]
+pub struct Problem {
+    pub path: String,
+    pub line: Option<usize>,
+    pub message: String,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.line {
+            Some(line) => write!(f, "line {}, {}: {}", line, self.path, self.message),
+            None => write!(f, "{}: {}", self.path, self.message),
+        }
+    }
+}
+
+/// Checks the parts of the config used by the given roles and returns all problems found.
+///
+/// If the TOML `source` is given, the problems are annotated with the line of the value.
+pub fn validate(config: &Config, source: Option<&str>, roles: &[Role]) -> Vec<Problem> {
+    let mut problems = Vec::new();
+    let mut report = |path: String, message: String| {
+        problems.push(Problem {
+            line: source.and_then(|s| locate(s, &path)),
+            path,
+            message,
+        })
+    };
+
+    let mut names: Vec<&String> = config.entries.keys().collect();
+    names.sort();
+    for name in names {
+        check_entry(
+            &format!("entries.{}", name),
+            &config.entries[name],
+            &mut report,
+        );
+    }
+
+    if roles.contains(&Role::Cache) {
+        if let Err(err) = check_writable(Path::new(&config.cache.root_path)) {
+            report("cache.root_path".to_owned(), err);
+        }
+    }
+
+    if roles.contains(&Role::Cache) {
+        for (i, parent) in config.cache.parents.iter().enumerate() {
+            if let Err(err) = check_url(parent) {
+                report(format!("cache.parents[{}]", i), err);
+            }
+        }
+
+        for (i, peer) in config.cache.peers.iter().enumerate() {
+            if let Err(err) = check_url(peer) {
+                report(format!("cache.peers[{}]", i), err);
+            }
+        }
+
+        if let Some(ref node_url) = config.cache.node_url {
+            if let Err(err) = check_url(node_url) {
+                report("cache.node_url".to_owned(), err);
+            }
+        }
+
+        if HeaderName::from_bytes(config.cache.node_id_header.as_bytes()).is_err() {
+            report(
+                "cache.node_id_header".to_owned(),
+                format!("Invalid header name {:?}", config.cache.node_id_header),
+            );
+        }
+
+        if let Some(ref node_id) = config.cache.node_id {
+            if HeaderValue::from_str(node_id).is_err() {
+                report(
+                    "cache.node_id".to_owned(),
+                    format!("Invalid header value {:?}", node_id),
+                );
+            }
+        }
+
+        check_bandwidth("cache.bandwidth", &config.cache.bandwidth, &mut report);
+        check_client_limits(
+            "cache.client_limits",
+            &config.cache.client_limits,
+            &mut report,
+        );
+    }
+
+    if roles.contains(&Role::Proxy) {
+        if config.proxy.nodes.is_empty() {
+            report("proxy.nodes".to_owned(), "No nodes configured".to_owned());
+        }
+
+        for (i, node) in config.proxy.nodes.iter().enumerate() {
+            if let Err(err) = check_url(node) {
+                report(format!("proxy.nodes[{}]", i), err);
+            }
+        }
+
+        check_client_limits(
+            "proxy.client_limits",
+            &config.proxy.client_limits,
+            &mut report,
+        );
+    }
+
+    if roles.contains(&Role::Cache)
+        && roles.contains(&Role::Proxy)
+        && config.cache.bind == config.proxy.bind
+    {
+        report(
+            "proxy.bind".to_owned(),
+            format!("Same address as cache.bind ({})", config.cache.bind),
+        );
+    }
+
+    problems
+}
+
+fn check_entry<F>(path: &str, entry: &Entry, report: &mut F)
+where
+    F: FnMut(String, String),
+{
+    check_base_url(&format!("{}.base_url", path), &entry.base_url, report);
+    for (i, mirror) in entry.origin.mirrors.iter().enumerate() {
+        check_base_url(
+            &format!("{}.origin.mirrors[{}].url", path, i),
+            &mirror.url,
+            report,
+        );
+    }
+
+    let weights = std::iter::once(entry.origin.weight)
+        .chain(entry.origin.mirrors.iter().map(|mirror| mirror.weight));
+    if entry.origin.strategy == OriginStrategy::Weighted && weights.sum::<u32>() == 0 {
+        report(
+            format!("{}.origin.weight", path),
+            "All origins have a weight of 0".to_owned(),
+        );
+    }
+
+    let globs = [
+        ("patterns", &entry.patterns),
+        ("headers.passthrough", &entry.headers.passthrough),
+        ("headers.strip", &entry.headers.strip),
+        ("compression.skip_types", &entry.compression.skip_types),
+    ];
+
+    for (key, patterns) in globs.iter() {
+        for (i, pattern) in patterns.iter().enumerate() {
+            if let Err(err) = Glob::new(pattern) {
+                report(format!("{}.{}[{}]", path, key, i), err.kind().to_string());
+            }
+        }
+    }
+
+    check_bandwidth(&format!("{}.bandwidth", path), &entry.bandwidth, report);
+    check_client_limits(
+        &format!("{}.client_limits", path),
+        &entry.client_limits,
+        report,
+    );
+    check_headers(&format!("{}.headers.add", path), &entry.headers.add, report);
+    check_headers(
+        &format!("{}.origin.headers", path),
+        &entry.origin.headers,
+        report,
+    );
+
+    if let Some(ref proxy) = entry.origin.proxy {
+        if let Err(err) = Url::parse(proxy) {
+            report(
+                format!("{}.origin.proxy", path),
+                format!("Invalid URL: {}", err),
+            );
+        }
+    }
+
+    let files = [
+        ("ca_bundle", &entry.origin.ca_bundle),
+        ("client_cert", &entry.origin.client_cert),
+    ];
+
+    for (key, file) in files.iter() {
+        if let Some(file) = file {
+            if let Err(err) = fs::File::open(file) {
+                report(
+                    format!("{}.origin.{}", path, key),
+                    format!("Cannot read {}: {}", file, err),
+                );
+            }
+        }
+    }
+}
+
+fn check_bandwidth<F>(path: &str, bandwidth: &BandwidthConfig, report: &mut F)
+where
+    F: FnMut(String, String),
+{
+    let limits = [
+        ("fill", bandwidth.fill),
+        ("serve", bandwidth.serve),
+        ("connection", bandwidth.connection),
+    ];
+
+    for (key, limit) in limits.iter() {
+        if *limit == Some(0) {
+            report(
+                format!("{}.{}", path, key),
+                "Must be above 0, leave it unset for no limit".to_owned(),
+            );
+        }
+    }
+}
+
+fn check_client_limits<F>(path: &str, limits: &ClientLimitsConfig, report: &mut F)
+where
+    F: FnMut(String, String),
+{
+    let values = [
+        ("rate", limits.rate),
+        ("burst", limits.burst),
+        ("connections", limits.connections),
+    ];
+
+    for (key, value) in values.iter() {
+        if *value == Some(0) {
+            report(
+                format!("{}.{}", path, key),
+                "Must be above 0, leave it unset for no limit".to_owned(),
+            );
+        }
+    }
+
+    for (i, net) in limits.trusted.iter().enumerate() {
+        if let Err(err) = net.parse::<Cidr>() {
+            report(format!("{}.trusted[{}]", path, i), err);
+        }
+    }
+}
+
+fn check_base_url<F>(path: &str, url: &str, report: &mut F)
+where
+    F: FnMut(String, String),
+{
+    match check_url(url) {
+        Err(err) => report(path.to_owned(), err),
+        Ok(()) if !url.ends_with('/') => report(
+            path.to_owned(),
+            "Missing trailing slash, files would be resolved relative to the parent".to_owned(),
+        ),
+        Ok(()) => {}
+    }
+}
+
+fn check_headers<F>(path: &str, headers: &BTreeMap<String, String>, report: &mut F)
+where
+    F: FnMut(String, String),
+{
+    for (name, value) in headers.iter() {
+        if HeaderName::from_bytes(name.as_bytes()).is_err() {
+            report(path.to_owned(), format!("Invalid header name {:?}", name));
+        } else if HeaderValue::from_str(value).is_err() {
+            report(
+                format!("{}.{}", path, name),
+                format!("Invalid header value {:?}", value),
+            );
+        }
+    }
+}
+
+fn check_url(url: &str) -> Result<(), String> {
+    let url = Url::parse(url).map_err(|err| format!("Invalid URL: {}", err))?;
+
+    match url.scheme() {
+        "http" | "https" => Ok(()),
+        scheme => Err(format!("Unsupported URL scheme {}", scheme)),
+    }
+}
+
+/// Checks that files can be created in `path` or, if it does not exist yet, in its
+/// closest existing ancestor
+fn check_writable(path: &Path) -> Result<(), String> {
+    let dir = path
+        .ancestors()
+        .find(|p| p.exists())
+        .unwrap_or_else(|| Path::new("."));
+
+    if !dir.is_dir() {
+        return Err(format!("{} is not a directory", dir.to_string_lossy()));
+    }
+
+    let probe = dir.join(format!(".bcdn-check-{}", std::process::id()));
+    fs::write(&probe, b"")
+        .and_then(|_| fs::remove_file(&probe))
+        .map_err(|err| format!("{} is not writable: {}", dir.to_string_lossy(), err))
+}
+
+/// Finds the line of the value at the dotted `path` in the TOML source, falling back to the
+/// closest enclosing table or key
+fn locate(source: &str, path: &str) -> Option<usize> {
+    let path = path.split('[').next().unwrap_or(path);
+    let mut table = String::new();
+    let mut best: Option<(usize, usize)> = None;
+
+    for (i, line) in source.lines().enumerate() {
+        let line = line.trim();
+
+        let key = if line.starts_with('[') {
+            table = normalize_key(line.trim_matches(|c| c == '[' || c == ']'));
+            table.clone()
+        } else if let Some(pos) = line.find('=').filter(|_| !line.starts_with('#')) {
+            let key = normalize_key(&line[..pos]);
+            if table.is_empty() {
+                key
+            } else {
+                format!("{}.{}", table, key)
+            }
+        } else {
+            continue;
+        };
+
+        if key == path {
+            return Some(i + 1);
+        }
+
+        let is_prefix = path.starts_with(&key) && path[key.len()..].starts_with('.');
+        if is_prefix && best.is_none_or(|(len, _)| key.len() > len) {
+            best = Some((key.len(), i + 1));
+        }
+    }
+
+    best.map(|(_, line)| line)
+}
+
+fn normalize_key(key: &str) -> String {
+    key.split('.')
+        .map(|part| part.trim().trim_matches(|c| c == '"' || c == '\''))
+        .collect::<Vec<_>>()
+        .join(".")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_locate() {
+        let source = r#"
+[cache]
+root_path = "./cache"
+
+[proxy]
+nodes = []
+
+[entries.nukular]
+base_url = "https://example.com"
+patterns = ["*.m4a", "[a"]
+
+[entries."other"]
+compression = { skip_types = ["["] }
+"#;
+
+        assert_eq!(locate(source, "cache.root_path"), Some(3));
+        assert_eq!(locate(source, "proxy.nodes"), Some(6));
+        assert_eq!(locate(source, "entries.nukular.base_url"), Some(9));
+        assert_eq!(locate(source, "entries.nukular.patterns[1]"), Some(10));
+        assert_eq!(locate(source, "entries.nukular.headers.add"), Some(8));
+        assert_eq!(
+            locate(source, "entries.other.compression.skip_types[0]"),
+            Some(13)
+        );
+        assert_eq!(locate(source, "unknown.key"), None);
+    }
+
+    #[test]
+    fn test_validate() {
+        let config: Config = toml::from_str(
+            r#"
+[cache]
+bind = "127.0.0.1:1337"
+root_path = "/tmp"
+
+[proxy]
+bind = "127.0.0.1:1337"
+nodes = ["ftp://node"]
+client_limits = { rate = 0, trusted = ["10.0.0.0/8", "10.0.0.0/40"] }
+
+[entries.a]
+base_url = "https://example.com/files"
+patterns = ["[a"]
+"#,
+        )
+        .unwrap();
+
+        let paths: Vec<String> = validate(&config, None, &[Role::Cache, Role::Proxy])
+            .into_iter()
+            .map(|p| p.path)
+            .collect();
+
+        assert_eq!(
+            paths,
+            vec![
+                "entries.a.base_url",
+                "entries.a.patterns[0]",
+                "proxy.nodes[0]",
+                "proxy.client_limits.rate",
+                "proxy.client_limits.trusted[1]",
+                "proxy.bind"
+            ]
+        );
+    }
+}
@@ -1,11 +1,23 @@
+use crate::config::Encoding;
+use crate::metrics;
 use crate::util::hash_serde;
+use crate::util::named_file::NamedFile;
+use actix_web::http::header::{EntityTag, HttpDate};
 use blake3::{Hash, Hasher};
 use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
 use std::fs;
 use std::io;
 use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
 use thiserror::Error;
 
+/// Version of the digest format written by this build, older versions are migrated on load.
+///
+/// - 1: `last_modified` and `cached_at` may be missing
+/// - 2: `last_modified` and `cached_at` are set for every complete file
+pub const VERSION: u32 = 2;
+
 #[derive(Deserialize, Serialize, Clone, Debug)]
 pub struct Digest {
     version: u32,
@@ -15,6 +27,24 @@ pub struct Digest {
     pub file_name: String,
     pub content_type: String,
 
+    /// Origin response headers that are replayed when serving the file
+    #[serde(default)]
+    pub headers: Vec<(String, String)>,
+
+    /// Content encoding of a compressed variant, `None` for the original file
+    #[serde(default)]
+    pub encoding: Option<Encoding>,
+
+    /// `ETag` of the origin response
+    #[serde(default)]
+    pub origin_etag: Option<String>,
+    /// `Last-Modified` of the origin response or the download time as HTTP date
+    #[serde(default)]
+    pub last_modified: Option<String>,
+    /// Time the file was stored in the cache, in seconds since the Unix epoch
+    #[serde(default)]
+    pub cached_at: Option<u64>,
+
     #[serde(with = "hash_serde")]
     hash: Hash,
 
@@ -23,19 +53,72 @@ pub struct Digest {
 }
 
 impl Digest {
-    fn from_file<P: AsRef<Path>>(path: P) -> Result<Digest, DigestError> {
+    /// Reads a digest file, migrating it in memory if it has an older version. Returns
+    /// whether it was migrated.
+    fn from_file<P: AsRef<Path>>(path: P) -> Result<(Digest, bool), DigestError> {
         let path = path.as_ref();
-        let root = path.parent().ok_or(DigestError::FileNotFound)?.to_owned();
+        let root = path.parent().ok_or(DigestError::FileNotFound)?;
 
         let text = std::fs::read_to_string(path)?;
-        let mut digest: Digest = serde_json::from_str(&text)?;
+        Self::from_value(serde_json::from_str(&text)?, root)
+    }
+
+    /// Reads a digest of any supported version for a file in `root`, migrating older
+    /// versions. Returns whether it was migrated.
+    pub fn from_value(
+        value: serde_json::Value,
+        root: &Path,
+    ) -> Result<(Digest, bool), DigestError> {
+        let version = value.get("version").and_then(|v| v.as_u64()).unwrap_or(1);
+        let version = u32::try_from(version).unwrap_or(u32::MAX);
+        if version > VERSION {
+            return Err(DigestError::UnsupportedVersion(version));
+        }
+
+        let mut digest: Digest = serde_json::from_value(value)?;
+        digest.root = root.to_owned();
+
+        if digest.version < 2 {
+            digest.migrate_v1()?;
+        }
+
+        digest.version = VERSION;
+        Ok((digest, version < VERSION))
+    }
 
-        digest.root = root;
+    /// Version 1 derived a missing `last_modified` from the file on every load and had no
+    /// `cached_at`, both are taken from the modification time of the file
+    fn migrate_v1(&mut self) -> io::Result<()> {
+        if self.last_modified.is_some() && self.cached_at.is_some() {
+            return Ok(());
+        }
+
+        let modified = fs::metadata(self.get_file_path())?.modified()?;
+        self.last_modified
+            .get_or_insert_with(|| HttpDate::from(modified).to_string());
+        if self.cached_at.is_none() {
+            self.cached_at = modified
+                .duration_since(UNIX_EPOCH)
+                .ok()
+                .map(|d| d.as_secs());
+        }
 
-        Ok(digest)
+        Ok(())
     }
 
+    /// Reads the digest of the file at `path`. Digests of older versions are migrated and
+    /// written back.
     pub fn for_path<P: AsRef<Path>>(path: P) -> Result<Digest, DigestError> {
+        Self::load(path).map(|(digest, _)| digest)
+    }
+
+    /// Migrates the digest of the file at `path` to the current version, returning whether
+    /// it was older
+    pub fn upgrade<P: AsRef<Path>>(path: P) -> Result<bool, DigestError> {
+        Self::load(path).map(|(_, migrated)| migrated)
+    }
+
+    fn load<P: AsRef<Path>>(path: P) -> Result<(Digest, bool), DigestError> {
         let path = path.as_ref();
         let file_name = path
             .file_name()
@@ -45,26 +128,118 @@ impl Digest {
 
         let digest_path = format!("{}.digest", file_name);
 
-        Self::from_file(
+        let (digest, migrated) = Self::from_file(
             path.parent()
                 .ok_or(DigestError::FileNotFound)?
                 .join(digest_path),
-        )
+        )?;
+
+        if migrated {
+            digest.write()?;
+            log::info!("Migrated the digest of {}", path.to_string_lossy());
+        }
+
+        Ok((digest, migrated))
     }
 
-    pub fn write<P: AsRef<Path>>(&self, root: P) -> Result<(), DigestError> {
-        let root = root.as_ref();
+    /// Replaces the digest file atomically, it is written to a staging file first
+    pub fn write(&self) -> Result<(), DigestError> {
+        let staged = self.stage()?;
+        fs::rename(staged, self.get_digest_path())?;
+        sync_dir(&self.root)?;
 
-        let digest_filename = format!("{}.digest", self.file_name);
-        let digest_path = root.join(digest_filename);
+        Ok(())
+    }
+
+    /// Moves the file of the digest, written to a staging path, to `path` and stores the
+    /// digest next to it.
+    ///
+    /// The file and the digest are flushed to disk before either is renamed, the staged
+    /// digest is renamed last. After a crash, `recover` finishes the commit if the file was
+    /// already renamed and rolls it back otherwise.
+    pub fn commit<P: AsRef<Path>>(&mut self, path: P) -> Result<(), DigestError> {
+        let path = path.as_ref();
+        let staged_file = self.get_file_path();
 
-        let file = fs::File::create(digest_path)?;
-        serde_json::to_writer_pretty(file, &self)?;
+        self.set_path(path)?;
+        fs::File::open(&staged_file)?.sync_all()?;
+        let staged_digest = self.stage()?;
+
+        fs::rename(staged_file, path)?;
+        fs::rename(staged_digest, self.get_digest_path())?;
+        sync_dir(&self.root)?;
 
         Ok(())
     }
 
-    pub fn new<P>(path: P, content_type: &str, hash: Hash) -> Self
+    /// Durably moves the file of the digest, written to a staging path, to `path` without
+    /// writing a digest file. `store` is called with the moved digest before the file is
+    /// renamed, so a crash never leaves a file its digest was not stored for.
+    pub fn move_to<P, F>(&mut self, path: P, store: F) -> Result<(), DigestError>
+    where
+        P: AsRef<Path>,
+        F: FnOnce(&Self) -> io::Result<()>,
+    {
+        let path = path.as_ref();
+        let staged_file = self.get_file_path();
+
+        self.set_path(path)?;
+        fs::File::open(&staged_file)?.sync_all()?;
+        store(self)?;
+        fs::rename(staged_file, path)?;
+        sync_dir(&self.root)?;
+
+        Ok(())
+    }
+
+    fn set_path(&mut self, path: &Path) -> Result<(), DigestError> {
+        self.file_name = path
+            .file_name()
+            .ok_or(DigestError::FileNotFound)?
+            .to_str()
+            .ok_or(DigestError::InvalidFileName)?
+            .to_owned();
+        self.root = path.parent().ok_or(DigestError::FileNotFound)?.to_owned();
+
+        Ok(())
+    }
+
+    /// Finishes or rolls back the commit of the staged digest at `path`. The commit is
+    /// finished if the file next to it is the one described, otherwise the staged digest is
+    /// dropped and the staged file is left to its owner.
+    ///
+    /// Returns whether the commit was finished.
+    pub fn recover<P: AsRef<Path>>(path: P) -> Result<bool, DigestError> {
+        let path = path.as_ref();
+        let digest = Self::from_file(path).and_then(|(digest, _)| {
+            digest.verify()?;
+            Ok(digest)
+        });
+
+        match digest {
+            Ok(digest) => {
+                fs::rename(path, digest.get_digest_path())?;
+                sync_dir(&digest.root)?;
+                Ok(true)
+            }
+            Err(_) => {
+                fs::remove_file(path)?;
+                Ok(false)
+            }
+        }
+    }
+
+    /// Writes the digest to its staging file and flushes it to disk
+    fn stage(&self) -> Result<PathBuf, DigestError> {
+        let path = self.get_staged_digest_path();
+        let mut file = fs::File::create(&path)?;
+        serde_json::to_writer_pretty(&mut file, &self)?;
+        file.sync_all()?;
+
+        Ok(path)
+    }
+
+    pub fn new<P>(path: P, content_type: &str, headers: Vec<(String, String)>, hash: Hash) -> Self
     where
         P: AsRef<Path>,
     {
@@ -73,18 +248,71 @@ impl Digest {
         let file_name = path.file_name().unwrap().to_str().unwrap().to_owned();
 
         let root = path.parent().unwrap().to_owned();
+        let last_modified = m.modified().ok().map(|t| HttpDate::from(t).to_string());
 
         Digest {
-            version: 1,
+            version: VERSION,
             size: m.len(),
             downloaded: m.len(),
             file_name,
             content_type: content_type.to_owned(),
+            headers,
+            encoding: None,
+            origin_etag: None,
+            last_modified,
+            cached_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .ok()
+                .map(|d| d.as_secs()),
             hash,
             root,
         }
     }
 
+    /// Sets the full size of the file for a partial download, whose `downloaded` bytes are
+    /// on disk
+    pub fn with_size(mut self, size: u64) -> Self {
+        self.size = size;
+        self
+    }
+
+    pub fn is_partial(&self) -> bool {
+        self.downloaded < self.size
+    }
+
+    pub fn with_encoding(mut self, encoding: Encoding) -> Self {
+        self.encoding = Some(encoding);
+        self
+    }
+
+    /// Takes over the validators of the origin response
+    pub fn with_validators(mut self, etag: Option<String>, last_modified: Option<String>) -> Self {
+        self.origin_etag = etag;
+        if last_modified.is_some() {
+            self.last_modified = last_modified;
+        }
+        self
+    }
+
+    pub fn hash(&self) -> Hash {
+        self.hash
+    }
+
+    /// Strong `ETag` derived from the hash, thus identical on all nodes
+    pub fn etag(&self) -> EntityTag {
+        EntityTag::strong(self.hash.to_hex().to_string())
+    }
+
+    /// Seconds since the file was stored in the cache
+    pub fn age(&self) -> Option<u64> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).ok()?.as_secs();
+        Some(now.saturating_sub(self.cached_at?))
+    }
+
+    pub fn last_modified(&self) -> Option<HttpDate> {
+        self.last_modified.as_ref()?.parse().ok()
+    }
+
     pub fn verify(&self) -> Result<(), DigestError> {
         let mut hasher = Hasher::new();
         let mut file = io::BufReader::new(fs::File::open(self.get_file_path())?);
@@ -92,18 +320,41 @@ impl Digest {
 
         let hash = hasher.finalize();
         if hash != self.hash {
+            metrics::VERIFY_FAILURES.inc();
             Err(DigestError::VerifyError)
         } else {
             Ok(())
         }
     }
 
-    pub fn serve(&self) -> impl actix_web::Responder {
-        use crate::util::named_file::NamedFile;
+    pub fn serve(&self, headers: Vec<(String, String)>) -> io::Result<NamedFile> {
+        let file = NamedFile::open(self.get_file_path())?;
+
+        let file = if self.content_type != "unknown" {
+            file.set_content_type(&self.content_type)
+        } else {
+            file
+        };
 
-        NamedFile::open(self.get_file_path())
-            .unwrap()
-           // .set_content_type(self.content_type.parse().unwrap())
+        let file = file.set_headers(headers).set_etag(self.etag());
+
+        if let Some(last_modified) = self.last_modified() {
+            Ok(file.set_last_modified(last_modified))
+        } else {
+            Ok(file)
+        }
+    }
+
+    /// Deletes the file and its digest
+    pub fn remove(&self) -> io::Result<()> {
+        for path in [self.get_file_path(), self.get_digest_path()].iter() {
+            match fs::remove_file(path) {
+                Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+                _ => {}
+            }
+        }
+
+        Ok(())
     }
 
     pub fn get_file_path(&self) -> PathBuf {
@@ -113,6 +364,19 @@ impl Digest {
     fn get_digest_path(&self) -> PathBuf {
         self.root.join(format!("{}.digest", self.file_name))
     }
+
+    fn get_staged_digest_path(&self) -> PathBuf {
+        self.root
+            .join(format!(".{}{}", self.file_name, STAGED_DIGEST_SUFFIX))
+    }
+}
+
+/// Suffix of a digest that is not committed yet, after a leading `.`
+pub const STAGED_DIGEST_SUFFIX: &str = ".digest.tmp";
+
+/// Flushes the entries of the directory at `path`, making renames in it durable
+pub fn sync_dir<P: AsRef<Path>>(path: P) -> io::Result<()> {
+    fs::File::open(path)?.sync_all()
 }
 
 fn default_root() -> PathBuf {
@@ -135,4 +399,46 @@ pub enum DigestError {
 
     #[error("Incorrect hash in digest")]
     VerifyError,
+
+    #[error(
+        "Digest version {0} is not supported, this build reads up to version {}",
+        VERSION
+    )]
+    UnsupportedVersion(u32),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::test_dir::TestDir;
+
+    #[test]
+    fn test_versions() {
+        let root = TestDir::new("digest");
+        fs::write(root.join("a"), "a").unwrap();
+
+        let digest = |version: u32| {
+            serde_json::json!({
+                "version": version,
+                "size": 1,
+                "downloaded": 1,
+                "file_name": "a",
+                "content_type": "text/plain",
+                "hash": blake3::hash(b"a").to_hex().to_string(),
+            })
+        };
+
+        let (v1, migrated) = Digest::from_value(digest(1), &root).unwrap();
+        assert!(migrated);
+        assert_eq!(v1.version, VERSION);
+        assert!(v1.last_modified.is_some() && v1.cached_at.is_some());
+
+        let (_, migrated) = Digest::from_value(digest(VERSION), &root).unwrap();
+        assert!(!migrated);
+
+        match Digest::from_value(digest(VERSION + 1), &root) {
+            Err(DigestError::UnsupportedVersion(version)) => assert_eq!(version, VERSION + 1),
+            res => panic!("Expected an unsupported version, got {:?}", res),
+        }
+    }
 }
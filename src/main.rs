@@ -1,14 +1,13 @@
 mod cache_server;
 mod config;
 mod digest;
+mod metrics;
 mod proxy_server;
 mod util;
 
-use config::Config;
+use config::{Loader, Role};
 
 use clap::{App, Arg, ArgMatches, SubCommand};
-use std::fs;
-use std::path::Path;
 
 fn main() -> Result<(), std::io::Error> {
     if std::env::var("RUST_LOG").is_err() {
@@ -19,15 +18,99 @@ fn main() -> Result<(), std::io::Error> {
     let m = App::new("bcdn")
         .subcommand(
             SubCommand::with_name("cache")
-                .subcommand(SubCommand::with_name("run"))
+                .subcommand(
+                    SubCommand::with_name("run")
+                        .arg(
+                            Arg::with_name("bind")
+                                .long("bind")
+                                .takes_value(true)
+                                .help("Address to listen on, overrides cache.bind"),
+                        )
+                        .arg(
+                            Arg::with_name("root-path")
+                                .long("root-path")
+                                .takes_value(true)
+                                .help("Directory of the cached files, overrides cache.root_path"),
+                        ),
+                )
                 .subcommand(SubCommand::with_name("install"))
-                .subcommand(SubCommand::with_name("clean")),
+                .subcommand(SubCommand::with_name("clean"))
+                .subcommand(
+                    SubCommand::with_name("index")
+                        .about("Convert the digests of a stopped cache node")
+                        .subcommand(
+                            SubCommand::with_name("import")
+                                .about("Move the .digest files into the index")
+                                .arg(Arg::with_name("entry")),
+                        )
+                        .subcommand(
+                            SubCommand::with_name("export")
+                                .about("Move the index into .digest files")
+                                .arg(Arg::with_name("entry")),
+                        ),
+                )
+                .subcommand(
+                    SubCommand::with_name("migrate")
+                        .about("Upgrade the digests of a stopped cache node to the current format")
+                        .arg(Arg::with_name("entry")),
+                )
+                .subcommand(
+                    SubCommand::with_name("warm")
+                        .about("Download files of an entry into a running cache node")
+                        .arg(Arg::with_name("entry").required(true))
+                        .arg(Arg::with_name("files").multiple(true))
+                        .arg(
+                            Arg::with_name("glob")
+                                .long("glob")
+                                .takes_value(true)
+                                .help("Warm all files in the origin's listing matching the glob"),
+                        )
+                        .arg(
+                            Arg::with_name("parallel")
+                                .long("parallel")
+                                .takes_value(true)
+                                .default_value("2"),
+                        )
+                        .arg(
+                            Arg::with_name("node")
+                                .long("node")
+                                .takes_value(true)
+                                .help("URL of the cache node, defaults to the configured bind"),
+                        ),
+                ),
         )
         .subcommand(
             SubCommand::with_name("proxy")
-                .subcommand(SubCommand::with_name("run"))
+                .subcommand(
+                    SubCommand::with_name("run")
+                        .arg(
+                            Arg::with_name("bind")
+                                .long("bind")
+                                .takes_value(true)
+                                .help("Address to listen on, overrides proxy.bind"),
+                        )
+                        .arg(
+                            Arg::with_name("node")
+                                .long("node")
+                                .takes_value(true)
+                                .multiple(true)
+                                .number_of_values(1)
+                                .help("URL of a cache node, replaces proxy.nodes"),
+                        ),
+                )
                 .subcommand(SubCommand::with_name("install")),
         )
+        .subcommand(
+            SubCommand::with_name("config")
+                .subcommand(
+                    SubCommand::with_name("check")
+                        .about("Validate the config and report all problems found"),
+                )
+                .subcommand(
+                    SubCommand::with_name("show")
+                        .about("Print the effective config and where each value came from"),
+                ),
+        )
         .arg(
             Arg::with_name("config")
                 .long("config")
@@ -37,13 +120,12 @@ fn main() -> Result<(), std::io::Error> {
         .about("Manage or run bcdn")
         .get_matches();
 
-    let cfg_path = m.value_of("config").unwrap();
-    let config = fs::read_to_string(Path::new(cfg_path))?;
-    let config: Config = toml::from_str(&config)?;
+    let loader = Loader::new(m.value_of("config").unwrap());
 
     match m.subcommand() {
-        ("cache", Some(matches)) => cache(config, matches),
-        ("proxy", Some(matches)) => proxy(config, matches),
+        ("cache", Some(matches)) => cache(loader, matches),
+        ("proxy", Some(matches)) => proxy(loader, matches),
+        ("config", Some(matches)) => config_command(loader, matches),
         _ => {
             println!("{}", m.usage());
             Ok(())
@@ -51,9 +133,49 @@ fn main() -> Result<(), std::io::Error> {
     }
 }
 
-fn cache(config: Config, matches: &ArgMatches) -> Result<(), std::io::Error> {
+fn cache(loader: Loader, matches: &ArgMatches) -> Result<(), std::io::Error> {
+    match matches.subcommand() {
+        ("run", Some(matches)) => {
+            let mut loader = loader;
+            if let Some(bind) = matches.value_of("bind") {
+                loader = loader.with_flag("cache.bind", bind.into(), "bind");
+            }
+            if let Some(root_path) = matches.value_of("root-path") {
+                loader = loader.with_flag("cache.root_path", root_path.into(), "root-path");
+            }
+
+            let layered = loader.load()?;
+            let config = layered.config()?;
+            config::check(&config, &layered, &[Role::Cache])?;
+            cache_server::run(config, loader)
+        }
+        ("warm", Some(matches)) => cache_server::warm(loader.load()?.config()?, matches),
+        ("index", Some(matches)) => cache_server::index(loader.load()?.config()?, matches),
+        ("migrate", Some(matches)) => cache_server::migrate(loader.load()?.config()?, matches),
+        _ => {
+            println!("{}", matches.usage());
+            Ok(())
+        }
+    }
+}
+
+fn proxy(loader: Loader, matches: &ArgMatches) -> Result<(), std::io::Error> {
     match matches.subcommand() {
-        ("run", _) => cache_server::run(config, matches),
+        ("run", Some(matches)) => {
+            let mut loader = loader;
+            if let Some(bind) = matches.value_of("bind") {
+                loader = loader.with_flag("proxy.bind", bind.into(), "bind");
+            }
+            if let Some(nodes) = matches.values_of("node") {
+                let nodes: Vec<&str> = nodes.collect();
+                loader = loader.with_flag("proxy.nodes", nodes.into(), "node");
+            }
+
+            let layered = loader.load()?;
+            let config = layered.config()?;
+            config::check(&config, &layered, &[Role::Proxy])?;
+            proxy_server::run(config, loader)
+        }
         _ => {
             println!("{}", matches.usage());
             Ok(())
@@ -61,9 +183,52 @@ fn cache(config: Config, matches: &ArgMatches) -> Result<(), std::io::Error> {
     }
 }
 
-fn proxy(config: Config, matches: &ArgMatches) -> Result<(), std::io::Error> {
+fn config_command(loader: Loader, matches: &ArgMatches) -> Result<(), std::io::Error> {
+    let path = loader.path().to_string_lossy().into_owned();
+
+    let layered = loader.load().map_err(|err| {
+        println!("{}: {}", path, err);
+        err
+    })?;
+    let config = layered.config().map_err(|err| {
+        println!("{}: {}", path, err);
+        err
+    })?;
+
     match matches.subcommand() {
-        ("run", _) => proxy_server::run(config, matches),
+        ("check", _) => {
+            let problems = config::problems(&config, &layered, &[Role::Cache, Role::Proxy]);
+            for problem in problems.iter() {
+                println!("{}: {}", path, problem);
+            }
+
+            if problems.is_empty() {
+                println!("{}: OK", path);
+                Ok(())
+            } else {
+                Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("Found {} problems in the config", problems.len()),
+                ))
+            }
+        }
+        ("show", _) => {
+            let value = toml::Value::try_from(&config)
+                .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err))?;
+
+            let mut values = Vec::new();
+            config::flatten(&value, "", &mut values);
+
+            for (key, value) in values {
+                let value = if config::is_secret(&key) {
+                    "\"***\"".to_owned()
+                } else {
+                    value
+                };
+                println!("{} = {}  # {}", key, value, layered.source_of(&key));
+            }
+            Ok(())
+        }
         _ => {
             println!("{}", matches.usage());
             Ok(())
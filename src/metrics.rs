@@ -0,0 +1,111 @@
+use actix_web::HttpResponse;
+use lazy_static::lazy_static;
+use prometheus::{
+    exponential_buckets, register_histogram_vec, register_int_counter, register_int_counter_vec,
+    register_int_gauge_vec, Encoder, HistogramTimer, HistogramVec, IntCounter, IntCounterVec,
+    IntGauge, IntGaugeVec, TextEncoder,
+};
+
+lazy_static! {
+    pub static ref CACHE_REQUESTS: IntCounterVec = register_int_counter_vec!(
+        "bcdn_cache_requests_total",
+        "Cache lookups by entry and result (hit, miss, error, not_found)",
+        &["entry", "result"]
+    )
+    .unwrap();
+    pub static ref SERVED_BYTES: IntCounterVec = register_int_counter_vec!(
+        "bcdn_served_bytes_total",
+        "Bytes of file content sent to clients",
+        &["entry"]
+    )
+    .unwrap();
+    pub static ref FETCHED_BYTES: IntCounterVec = register_int_counter_vec!(
+        "bcdn_fetched_bytes_total",
+        "Bytes downloaded from the origin",
+        &["entry"]
+    )
+    .unwrap();
+    pub static ref DOWNLOAD_DURATION: HistogramVec = register_histogram_vec!(
+        "bcdn_download_duration_seconds",
+        "Duration of downloads from the origin",
+        &["entry"],
+        exponential_buckets(0.1, 2.0, 12).unwrap()
+    )
+    .unwrap();
+    pub static ref DOWNLOADS_IN_FLIGHT: IntGaugeVec = register_int_gauge_vec!(
+        "bcdn_downloads_in_flight",
+        "Currently running downloads from the origin",
+        &["entry"]
+    )
+    .unwrap();
+    pub static ref CACHED_FILES: IntGaugeVec = register_int_gauge_vec!(
+        "bcdn_cached_files",
+        "Number of cached files, not counting compressed variants",
+        &["entry"]
+    )
+    .unwrap();
+    pub static ref CACHE_SIZE: IntGaugeVec = register_int_gauge_vec!(
+        "bcdn_cache_size_bytes",
+        "Size of the cached files including compressed variants",
+        &["entry"]
+    )
+    .unwrap();
+    pub static ref REDIRECTS: IntCounterVec = register_int_counter_vec!(
+        "bcdn_proxy_redirects_total",
+        "Redirects issued by the proxy by entry and node",
+        &["entry", "node"]
+    )
+    .unwrap();
+    pub static ref LIMITED_REQUESTS: IntCounterVec = register_int_counter_vec!(
+        "bcdn_limited_requests_total",
+        "Requests rejected by the per-client limits by reason",
+        &["reason"]
+    )
+    .unwrap();
+    pub static ref VERIFY_FAILURES: IntCounter = register_int_counter!(
+        "bcdn_digest_verify_failures_total",
+        "Files whose content did not match the hash in their digest"
+    )
+    .unwrap();
+}
+
+/// Counts a download of an entry as in flight and times it until dropped, so a download
+/// cancelled by a client disconnect is accounted for as well
+pub struct DownloadTimer {
+    in_flight: IntGauge,
+    _timer: HistogramTimer,
+}
+
+impl DownloadTimer {
+    pub fn start(entry: &str) -> Self {
+        let in_flight = DOWNLOADS_IN_FLIGHT.with_label_values(&[entry]);
+        in_flight.inc();
+
+        DownloadTimer {
+            in_flight,
+            _timer: DOWNLOAD_DURATION.with_label_values(&[entry]).start_timer(),
+        }
+    }
+}
+
+impl Drop for DownloadTimer {
+    fn drop(&mut self) {
+        self.in_flight.dec();
+    }
+}
+
+/// Renders all registered metrics in the Prometheus text format
+pub fn render() -> HttpResponse {
+    // Unlabelled metrics are only registered on first use
+    lazy_static::initialize(&VERIFY_FAILURES);
+
+    let encoder = TextEncoder::new();
+    let mut buffer = Vec::new();
+
+    match encoder.encode(&prometheus::gather(), &mut buffer) {
+        Ok(()) => HttpResponse::Ok()
+            .content_type(encoder.format_type())
+            .body(buffer),
+        Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
+    }
+}
@@ -0,0 +1,146 @@
+use crate::util::{bearer, file_name};
+use actix_web::dev::Service;
+use actix_web::http::header;
+use actix_web::web::{self, Bytes};
+use actix_web::HttpResponse;
+use futures_util::future::{join_all, ready, Either};
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use std::sync::RwLock;
+use url::Url;
+
+/// Cache nodes the admin requests are fanned out to
+pub struct Nodes {
+    client: Client,
+    urls: RwLock<Vec<Url>>,
+    token: String,
+}
+
+impl Nodes {
+    pub fn new(nodes: &[String], token: &str) -> Result<Self, url::ParseError> {
+        Ok(Nodes {
+            client: Client::new(),
+            urls: RwLock::new(parse_urls(nodes)?),
+            token: token.to_owned(),
+        })
+    }
+
+    /// Replaces the nodes after the config was reloaded
+    pub fn set_urls(&self, urls: Vec<Url>) {
+        *self.urls.write().unwrap() = urls;
+    }
+}
+
+pub fn parse_urls(nodes: &[String]) -> Result<Vec<Url>, url::ParseError> {
+    nodes.iter().map(|n| Url::parse(n)).collect()
+}
+
+#[derive(Serialize)]
+struct NodePurgeResult {
+    node: String,
+    status: Option<u16>,
+    purged: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct PurgeResult {
+    purged: Vec<String>,
+}
+
+/// Registers the admin API below `/admin/v1`, requiring the token of `nodes` as bearer
+/// token, which is also used towards the cache nodes
+pub fn configure(nodes: web::Data<Nodes>, cfg: &mut web::ServiceConfig) {
+    let token = nodes.token.clone();
+
+    cfg.service(
+        web::scope("/admin/v1")
+            .wrap_fn(move |req, srv| {
+                if bearer::is_authorized(req.headers(), &token) {
+                    Either::Left(srv.call(req))
+                } else {
+                    Either::Right(ready(Ok(req.into_response(bearer::unauthorized()))))
+                }
+            })
+            .app_data(nodes)
+            .route("/{entry}/purge", web::post().to(purge)),
+    );
+}
+
+/// Forwards the purge request to all nodes and reports their results
+async fn purge(entry: web::Path<String>, body: Bytes, nodes: web::Data<Nodes>) -> HttpResponse {
+    if !file_name::is_valid(&entry) {
+        return HttpResponse::BadRequest().body("Invalid entry name");
+    }
+
+    // A single file to purge is checked like the cache nodes check file names
+    let file = serde_json::from_slice::<serde_json::Value>(&body)
+        .ok()
+        .and_then(|request| request.get("file").cloned());
+    if let Some(file) = file {
+        if !file.as_str().is_some_and(file_name::is_valid) {
+            return HttpResponse::BadRequest().body("Invalid file name");
+        }
+    }
+
+    let path = format!("admin/v1/{}/purge", entry);
+    let urls = nodes.urls.read().unwrap().clone();
+    let urls = match urls
+        .iter()
+        .map(|node| node.join(&path).map(|url| (node, url)))
+        .collect::<Result<Vec<_>, _>>()
+    {
+        Ok(urls) => urls,
+        Err(err) => return HttpResponse::BadRequest().body(err.to_string()),
+    };
+
+    let requests = urls.into_iter().map(|(node, url)| {
+        let request = nodes
+            .client
+            .post(url)
+            .bearer_auth(&nodes.token)
+            .header(header::CONTENT_TYPE.as_str(), "application/json")
+            .body(body.to_vec());
+
+        async move {
+            let mut res = NodePurgeResult {
+                node: node.to_string(),
+                status: None,
+                purged: Vec::new(),
+                error: None,
+            };
+
+            match request.send().await {
+                Ok(resp) => {
+                    let status = resp.status();
+                    res.status = Some(status.as_u16());
+
+                    if status.is_success() {
+                        match resp.json::<PurgeResult>().await {
+                            Ok(result) => res.purged = result.purged,
+                            Err(err) => res.error = Some(err.to_string()),
+                        }
+                    } else {
+                        res.error = Some(resp.text().await.unwrap_or_default());
+                    }
+                }
+                Err(err) => res.error = Some(err.to_string()),
+            }
+
+            res
+        }
+    });
+
+    let results = join_all(requests).await;
+
+    for res in results.iter().filter(|r| r.error.is_some()) {
+        log::error!("Failed to purge {} on {}: {:?}", entry, res.node, res.error);
+    }
+
+    if results.iter().all(|r| r.error.is_none()) {
+        HttpResponse::Ok().json(results)
+    } else {
+        HttpResponse::BadGateway().json(results)
+    }
+}
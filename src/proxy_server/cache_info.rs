@@ -1,11 +1,51 @@
-use crate::config::Config;
+use crate::config::{Config, ConfigError};
+use crate::metrics;
 use globset::GlobSet;
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
 use url::Url;
 
+/// Routing information of all entries, rebuilt when the config is reloaded
+pub struct CacheInfos {
+    entries: RwLock<HashMap<String, Arc<CacheInfo>>>,
+}
+
+impl CacheInfos {
+    pub fn new(config: &Config) -> Result<Self, ConfigError> {
+        Ok(CacheInfos {
+            entries: RwLock::new(Self::build(config)?),
+        })
+    }
+
+    pub fn get(&self, name: &str) -> Option<Arc<CacheInfo>> {
+        self.entries.read().unwrap().get(name).cloned()
+    }
+
+    pub fn reload(&self, config: &Config) -> Result<(), ConfigError> {
+        let entries = Self::build(config)?;
+        log::info!(
+            "Routing {} entries to {} nodes",
+            entries.len(),
+            config.proxy.nodes.len()
+        );
+
+        *self.entries.write().unwrap() = entries;
+        Ok(())
+    }
+
+    fn build(config: &Config) -> Result<HashMap<String, Arc<CacheInfo>>, ConfigError> {
+        config
+            .entries
+            .keys()
+            .map(|name| Ok((name.clone(), Arc::new(CacheInfo::new(name, config)?))))
+            .collect()
+    }
+}
+
 pub struct CacheInfo {
     nodes: Vec<NodeCacheInfo>,
 
-    name: String,
+    pub name: String,
     patterns: GlobSet,
 }
 
@@ -14,32 +54,36 @@ pub struct NodeCacheInfo {
 }
 
 impl NodeCacheInfo {
-    fn new(url: Url, config: &Config) -> Self {
+    fn new(url: Url) -> Self {
         NodeCacheInfo { url }
     }
 }
 
 impl CacheInfo {
-    pub fn new(name: &str, config: &Config) -> Self {
+    pub fn new(name: &str, config: &Config) -> Result<Self, ConfigError> {
         let nodes = config
             .proxy
             .nodes
             .iter()
-            .map(|n| NodeCacheInfo::new(Url::parse(n).unwrap(), config))
-            .collect();
+            .map(|n| Ok(NodeCacheInfo::new(Url::parse(n)?)))
+            .collect::<Result<_, ConfigError>>()?;
 
-        let entry = &config.entries[name];
-        let patterns = entry.get_globset().unwrap();
+        let entry = config
+            .entries
+            .get(name)
+            .ok_or_else(|| ConfigError::UnknownEntry(name.to_owned()))?;
+        let patterns = entry.get_globset()?;
         let name = name.to_owned();
 
-        CacheInfo {
+        Ok(CacheInfo {
             name,
             nodes,
             patterns,
-        }
+        })
     }
 
-    pub fn get_redirect(&self, filename: &str) -> Option<Url> {
+    /// Chooses the node to redirect a request for `filename` to
+    pub fn get_redirect(&self, filename: &str) -> Option<Redirect> {
         if !self.patterns.is_match(filename) {
             return None;
         }
@@ -53,6 +97,26 @@ impl CacheInfo {
 
         let path = format!("c/v1/{}/f/{}", self.name, filename);
 
-        Some(node.url.join(&path).unwrap())
+        metrics::REDIRECTS
+            .with_label_values(&[&self.name, node.url.as_str()])
+            .inc();
+
+        Some(Redirect {
+            url: node.url.join(&path).unwrap(),
+            reason: "random",
+        })
+    }
+}
+
+/// Where a request is redirected to and why that node was chosen
+pub struct Redirect {
+    pub url: Url,
+    pub reason: &'static str,
+}
+
+impl Redirect {
+    /// The chosen node as scheme, host and port
+    pub fn node(&self) -> String {
+        self.url.origin().ascii_serialization()
     }
 }
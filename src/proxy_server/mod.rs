@@ -1,42 +1,146 @@
-use crate::config::Config;
-use actix_web::{http, web, App, Either, HttpResponse, HttpServer, Responder};
+use crate::config::{self, Config, Loader, ProxyConfig, Role};
+use crate::metrics;
+use crate::util::access_log::{AccessInfo, AccessLog};
+use crate::util::client_limits::{self, Limiters};
+use actix_web::dev::Service;
+use actix_web::{http, web, App, Either, HttpRequest, HttpResponse, HttpServer, Responder};
+use std::io;
+use std::sync::Arc;
+mod admin;
 mod cache_info;
-use cache_info::CacheInfo;
+use cache_info::CacheInfos;
+
+/// Header naming the node a request is redirected to and the reason for choosing it
+const X_CACHE_ROUTE: &str = "x-cache-route";
 
 #[actix_rt::main]
-pub async fn run(config: Config, _matches: &clap::ArgMatches<'_>) -> std::io::Result<()> {
+pub async fn run(config: Config, loader: Loader) -> io::Result<()> {
     let bind = config.proxy.bind.clone();
 
     log::info!("Starting CDN proxy at {}...", bind);
 
+    let nodes = match config.proxy.admin_token {
+        Some(ref token) => admin::Nodes::new(&config.proxy.nodes, token)
+            .map(|nodes| Some(web::Data::new(nodes)))
+            .map_err(|err| io::Error::other(err.to_string()))?,
+        None => None,
+    };
+
+    let access_log = match config.proxy.access_log {
+        Some(ref log) => Some(Arc::new(AccessLog::open(log)?)),
+        None => None,
+    };
+
+    let cache_infos = CacheInfos::new(&config).map_err(|err| io::Error::other(err.to_string()))?;
+    let cache_infos = web::Data::new(cache_infos);
+    let limiters = Arc::new(Limiters::new(&config.proxy.client_limits, &config));
+    {
+        let (cache_infos, nodes, limiters) = (cache_infos.clone(), nodes.clone(), limiters.clone());
+        let started = config.proxy.clone();
+        config::reload_on_hangup(loader, &[Role::Proxy], move |config| {
+            reload(
+                &config,
+                &started,
+                &cache_infos,
+                nodes.as_ref().map(|nodes| nodes.get_ref()),
+                &limiters,
+            );
+            async {}
+        });
+    }
+
     HttpServer::new(move || {
-        let config = config.clone();
-        App::new().service(web::scope("/c/v1").configure(|cfg| configure(&config, cfg)))
-        // .service(cache_scope)
+        let (access_log, limiters) = (access_log.clone(), limiters.clone());
+        App::new()
+            .wrap_fn(move |req, srv| {
+                client_limits::limit(&limiters, req, srv, |_| {
+                    HttpResponse::TooManyRequests().body("Too many requests")
+                })
+            })
+            .wrap_fn(move |req, srv| {
+                let pending = access_log.as_ref().map(|log| log.start(&req));
+                let fut = srv.call(req);
+                async move {
+                    let res = fut.await?;
+                    if let Some(pending) = pending {
+                        pending.finish(&res);
+                    }
+                    Ok(res)
+                }
+            })
+            .route("/metrics", web::get().to(metrics::render))
+            .app_data(cache_infos.clone())
+            .route("/c/v1/{entry}/f/{filename}", web::get().to(data))
+            .route("/c/v1/{entry}/f/{filename}", web::head().to(data))
+            .configure(|cfg| {
+                if let Some(ref nodes) = nodes {
+                    admin::configure(nodes.clone(), cfg)
+                }
+            })
     })
     .bind(bind)?
     .run()
     .await
 }
 
-fn configure(config: &Config, cfg: &mut web::ServiceConfig) {
-    for name in config.entries.keys() {
-        let cache_info = CacheInfo::new(name, &config);
-        let own_scope = web::scope(name)
-            .data(cache_info)
-            .route("/f/{filename}", web::get().to(data));
+/// Applies a reloaded config, leaving the previous one in place if any part of it fails.
+/// `started` holds the settings the server was started with, which stay in effect.
+fn reload(
+    config: &Config,
+    started: &ProxyConfig,
+    cache_infos: &CacheInfos,
+    nodes: Option<&admin::Nodes>,
+    limiters: &Limiters,
+) {
+    if config.proxy.bind != started.bind
+        || config.proxy.admin_token != started.admin_token
+        || config.proxy.access_log != started.access_log
+    {
+        log::warn!("Changes to bind, admin_token and access_log require a restart");
+    }
+
+    let urls = match admin::parse_urls(&config.proxy.nodes) {
+        Ok(urls) => urls,
+        Err(err) => {
+            log::error!("Failed to parse nodes, aborting the reload: {}", err);
+            return;
+        }
+    };
+    if let Err(err) = cache_infos.reload(config) {
+        log::error!("Failed to reload entries, aborting the reload: {}", err);
+        return;
+    }
 
-        cfg.service(own_scope);
+    if let Some(nodes) = nodes {
+        nodes.set_urls(urls);
     }
+    limiters.reload(&config.proxy.client_limits, config);
 }
 
-async fn data(path: web::Path<String>, cache_info: web::Data<CacheInfo>) -> impl Responder {
-    let cache_info = cache_info.as_ref();
+async fn data(
+    req: HttpRequest,
+    path: web::Path<(String, String)>,
+    cache_infos: web::Data<CacheInfos>,
+) -> impl Responder {
+    let (entry, filename) = path.as_ref();
+    let cache_info = match cache_infos.get(entry) {
+        Some(cache_info) => cache_info,
+        None => return Either::B(HttpResponse::NotFound().body("Not found")),
+    };
+    let mut info = AccessInfo::new(&cache_info.name, filename);
+
+    let redirect = cache_info.get_redirect(filename);
+    info.node = redirect.as_ref().map(|redirect| redirect.node());
+    info.record(&req);
 
-    if let Some(redirect) = cache_info.get_redirect(path.as_ref()) {
+    if let Some(redirect) = redirect {
         Either::A(
             HttpResponse::TemporaryRedirect()
-                .header(http::header::LOCATION, redirect.to_string())
+                .header(http::header::LOCATION, redirect.url.to_string())
+                .header(
+                    X_CACHE_ROUTE,
+                    format!("{}; reason={}", redirect.node(), redirect.reason),
+                )
                 .body("Redirect"),
         )
     } else {
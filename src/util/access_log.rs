@@ -0,0 +1,336 @@
+use crate::config::{AccessLogConfig, LogFormat};
+use actix_web::dev::{BodySize, MessageBody, ServiceRequest, ServiceResponse};
+use actix_web::http::{header, Method};
+use actix_web::HttpRequest;
+use serde::Serialize;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+/// Details about a request only known to the handler, stored in the request extensions and
+/// picked up when the request is logged
+#[derive(Clone, Debug, Default)]
+pub struct AccessInfo {
+    pub entry: Option<String>,
+    pub filename: Option<String>,
+    /// Whether the file was served from the cache (`hit`) or downloaded first or not served
+    /// from the cache at all (`miss`)
+    pub outcome: Option<&'static str>,
+    /// Node the proxy redirected to
+    pub node: Option<String>,
+}
+
+impl AccessInfo {
+    pub fn new(entry: &str, filename: &str) -> Self {
+        AccessInfo {
+            entry: Some(entry.to_owned()),
+            filename: Some(filename.to_owned()),
+            ..Default::default()
+        }
+    }
+
+    /// Attaches the info to the request
+    pub fn record(self, req: &HttpRequest) {
+        req.extensions_mut().insert(self);
+    }
+}
+
+/// Access log file of a server in one of the `LogFormat`s
+pub struct AccessLog {
+    format: LogFormat,
+    file: Mutex<RotatingFile>,
+}
+
+impl AccessLog {
+    pub fn open(config: &AccessLogConfig) -> io::Result<Self> {
+        Ok(AccessLog {
+            format: config.format,
+            file: Mutex::new(RotatingFile::open(
+                PathBuf::from(&config.path),
+                config.max_size,
+                config.max_files,
+            )?),
+        })
+    }
+
+    /// Captures the request details before the request is passed on to the handler
+    pub fn start(self: &Arc<Self>, req: &ServiceRequest) -> PendingEntry {
+        let header = |name| {
+            req.headers()
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .map(|v| v.to_owned())
+        };
+
+        PendingEntry {
+            log: self.clone(),
+            started: Instant::now(),
+            time: SystemTime::now(),
+            remote: req.peer_addr().map(|addr| addr.ip().to_string()),
+            method: req.method().clone(),
+            path: req
+                .uri()
+                .path_and_query()
+                .map_or_else(|| req.path().to_owned(), |p| p.as_str().to_owned()),
+            version: format!("{:?}", req.version()),
+            range: header(header::RANGE),
+            referer: header(header::REFERER),
+            user_agent: header(header::USER_AGENT),
+        }
+    }
+
+    fn write(&self, line: &str) {
+        let mut file = self.file.lock().unwrap();
+
+        if let Err(err) = file.write_line(line) {
+            log::error!("Failed to write access log: {}", err);
+        }
+    }
+}
+
+/// A request whose response is not yet known
+pub struct PendingEntry {
+    log: Arc<AccessLog>,
+    started: Instant,
+    time: SystemTime,
+    remote: Option<String>,
+    method: Method,
+    path: String,
+    version: String,
+    range: Option<String>,
+    referer: Option<String>,
+    user_agent: Option<String>,
+}
+
+impl PendingEntry {
+    /// Writes the log line for the response
+    pub fn finish<B: MessageBody>(self, res: &ServiceResponse<B>) {
+        let info = res
+            .request()
+            .extensions()
+            .get::<AccessInfo>()
+            .cloned()
+            .unwrap_or_default();
+
+        let bytes = if self.method == Method::HEAD {
+            Some(0)
+        } else {
+            match res.response().body().size() {
+                BodySize::None | BodySize::Empty => Some(0),
+                BodySize::Sized(size) => Some(size),
+                // Streamed without known length, e.g. compressed on the fly
+                BodySize::Stream => None,
+            }
+        };
+
+        let line = self.format(res.status().as_u16(), bytes, &info);
+        self.log.write(&line);
+    }
+
+    fn format(&self, status: u16, bytes: Option<u64>, info: &AccessInfo) -> String {
+        let latency_ms = self.started.elapsed().as_secs_f64() * 1000.0;
+
+        match self.log.format {
+            LogFormat::Common | LogFormat::Combined => {
+                let mut line = format!(
+                    "{} - - [{}] \"{} {} {}\" {} {}",
+                    dash(self.remote.as_deref()),
+                    clf_time(self.time),
+                    self.method,
+                    self.path,
+                    self.version,
+                    status,
+                    bytes.map_or_else(|| "-".to_owned(), |b| b.to_string()),
+                );
+
+                if let LogFormat::Combined = self.log.format {
+                    line.push_str(&format!(
+                        " \"{}\" \"{}\"",
+                        dash(self.referer.as_deref()),
+                        dash(self.user_agent.as_deref())
+                    ));
+                }
+
+                line.push_str(&format!(
+                    " {} {} \"{}\" {} {:.3}",
+                    dash(info.entry.as_deref()),
+                    dash(info.outcome),
+                    dash(self.range.as_deref()),
+                    dash(info.node.as_deref()),
+                    latency_ms
+                ));
+                line
+            }
+            LogFormat::Json => {
+                let line = JsonLine {
+                    time: rfc3339_time(self.time),
+                    remote: self.remote.as_deref(),
+                    method: self.method.as_str(),
+                    path: &self.path,
+                    status,
+                    bytes,
+                    range: self.range.as_deref(),
+                    entry: info.entry.as_deref(),
+                    filename: info.filename.as_deref(),
+                    outcome: info.outcome,
+                    node: info.node.as_deref(),
+                    latency_ms: (latency_ms * 1000.0).round() / 1000.0,
+                    referer: self.referer.as_deref(),
+                    user_agent: self.user_agent.as_deref(),
+                };
+                serde_json::to_string(&line).unwrap()
+            }
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct JsonLine<'a> {
+    time: String,
+    remote: Option<&'a str>,
+    method: &'a str,
+    path: &'a str,
+    status: u16,
+    bytes: Option<u64>,
+    range: Option<&'a str>,
+    entry: Option<&'a str>,
+    filename: Option<&'a str>,
+    outcome: Option<&'a str>,
+    node: Option<&'a str>,
+    latency_ms: f64,
+    referer: Option<&'a str>,
+    user_agent: Option<&'a str>,
+}
+
+fn dash(value: Option<&str>) -> &str {
+    value.unwrap_or("-")
+}
+
+/// Log file that is rotated to `{path}.1`, `{path}.2`, … once it exceeds `max_size` bytes,
+/// keeping at most `max_files` old files
+struct RotatingFile {
+    path: PathBuf,
+    max_size: u64,
+    max_files: usize,
+    file: File,
+    size: u64,
+}
+
+impl RotatingFile {
+    fn open(path: PathBuf, max_size: u64, max_files: usize) -> io::Result<Self> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+
+        Ok(RotatingFile {
+            path,
+            max_size,
+            max_files,
+            file,
+            size,
+        })
+    }
+
+    fn write_line(&mut self, line: &str) -> io::Result<()> {
+        if self.size > 0 && self.size + line.len() as u64 + 1 > self.max_size {
+            self.rotate()?;
+        }
+
+        self.file.write_all(format!("{}\n", line).as_bytes())?;
+        self.size += line.len() as u64 + 1;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        let rotated = |n: usize| {
+            let mut path = self.path.clone().into_os_string();
+            path.push(format!(".{}", n));
+            PathBuf::from(path)
+        };
+
+        if self.max_files == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            for n in (1..self.max_files).rev() {
+                match fs::rename(rotated(n), rotated(n + 1)) {
+                    Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+                    _ => {}
+                }
+            }
+            fs::rename(&self.path, rotated(1))?;
+        }
+
+        self.file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+/// Splits the time into UTC year, month, day, hour, minute and second
+fn civil_time(time: SystemTime) -> (i64, u32, u32, u64, u64, u64) {
+    let secs = time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+    let (days, rem) = ((secs / 86400) as i64, secs % 86400);
+
+    // Civil from days, see http://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    (year, month, day, rem / 3600, rem % 3600 / 60, rem % 60)
+}
+
+fn clf_time(time: SystemTime) -> String {
+    let (year, month, day, hour, minute, second) = civil_time(time);
+    format!(
+        "{:02}/{}/{}:{:02}:{:02}:{:02} +0000",
+        day,
+        MONTHS[month as usize - 1],
+        year,
+        hour,
+        minute,
+        second
+    )
+}
+
+fn rfc3339_time(time: SystemTime) -> String {
+    let (year, month, day, hour, minute, second) = civil_time(time);
+    format!(
+        "{}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year, month, day, hour, minute, second
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_time() {
+        let time = UNIX_EPOCH + Duration::from_secs(971_186_136);
+        assert_eq!(clf_time(time), "10/Oct/2000:13:55:36 +0000");
+        assert_eq!(rfc3339_time(time), "2000-10-10T13:55:36Z");
+
+        let time = UNIX_EPOCH + Duration::from_secs(1_709_208_000);
+        assert_eq!(rfc3339_time(time), "2024-02-29T12:00:00Z");
+        assert_eq!(rfc3339_time(UNIX_EPOCH), "1970-01-01T00:00:00Z");
+    }
+}
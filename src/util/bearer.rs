@@ -0,0 +1,18 @@
+use actix_web::http::header::{self, HeaderMap};
+use actix_web::HttpResponse;
+
+/// Returns whether the request headers carry `token` as bearer token. The tokens are
+/// compared by their hashes, whose comparison takes the same time for any input.
+pub fn is_authorized(headers: &HeaderMap, token: &str) -> bool {
+    headers
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .is_some_and(|v| blake3::hash(v.as_bytes()) == blake3::hash(token.as_bytes()))
+}
+
+pub fn unauthorized() -> HttpResponse {
+    HttpResponse::Unauthorized()
+        .header(header::WWW_AUTHENTICATE, "Bearer")
+        .finish()
+}
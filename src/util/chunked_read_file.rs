@@ -3,14 +3,18 @@ use std::future::Future;
 use std::io::{Read, Seek};
 use std::pin::Pin;
 use std::task::{Context, Poll};
+use std::time::Duration;
 use std::{cmp, io};
 
+use super::throttle::Throttle;
+use actix_rt::time::{delay_for, Delay};
 use actix_web::error::{BlockingError, Error, ErrorInternalServerError};
 use actix_web::web;
 use futures_util::{
     future::{FutureExt, LocalBoxFuture},
     stream::Stream,
 };
+use prometheus::IntCounter;
 use web::Bytes;
 
 fn handle_error(err: BlockingError<io::Error>) -> Error {
@@ -20,18 +24,30 @@ fn handle_error(err: BlockingError<io::Error>) -> Error {
     }
 }
 
+type ChunkFuture = LocalBoxFuture<'static, Result<(File, Bytes), BlockingError<io::Error>>>;
+
 pub struct ChunkedReadFile {
     pub size: u64,
     pub offset: u64,
     pub file: Option<File>,
-    pub fut: Option<LocalBoxFuture<'static, Result<(File, Bytes), BlockingError<io::Error>>>>,
+    pub fut: Option<ChunkFuture>,
     pub counter: u64,
+    pub bytes_counter: Option<IntCounter>,
+    pub throttle: Throttle,
+    pub delay: Option<Pin<Box<Delay>>>,
 }
 
 impl Stream for ChunkedReadFile {
     type Item = Result<Bytes, Error>;
 
     fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        if let Some(ref mut delay) = self.delay {
+            match delay.as_mut().poll(cx) {
+                Poll::Ready(()) => self.delay = None,
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+
         if let Some(ref mut fut) = self.fut {
             return match Pin::new(fut).poll(cx) {
                 Poll::Ready(Ok((file, bytes))) => {
@@ -39,6 +55,14 @@ impl Stream for ChunkedReadFile {
                     self.file = Some(file);
                     self.offset += bytes.len() as u64;
                     self.counter += bytes.len() as u64;
+                    if let Some(ref bytes_counter) = self.bytes_counter {
+                        bytes_counter.inc_by(bytes.len() as u64);
+                    }
+                    // Sends the chunk right away and waits before reading the next one
+                    let wait = self.throttle.take(bytes.len() as u64);
+                    if wait > Duration::from_secs(0) {
+                        self.delay = Some(Box::pin(delay_for(wait)));
+                    }
                     Poll::Ready(Some(Ok(bytes)))
                 }
                 Poll::Ready(Err(e)) => Poll::Ready(Some(Err(handle_error(e)))),
@@ -56,8 +80,7 @@ impl Stream for ChunkedReadFile {
             let mut file = self.file.take().expect("Use after completion");
             self.fut = Some(
                 web::block(move || {
-                    let max_bytes: usize;
-                    max_bytes = cmp::min(size.saturating_sub(counter), 65_536) as usize;
+                    let max_bytes = cmp::min(size.saturating_sub(counter), 65_536) as usize;
                     let mut buf = Vec::with_capacity(max_bytes);
                     file.seek(io::SeekFrom::Start(offset))?;
                     let nbytes = file.by_ref().take(max_bytes as u64).read_to_end(&mut buf)?;
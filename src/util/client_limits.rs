@@ -0,0 +1,470 @@
+use crate::config::{ClientLimitsConfig, Config};
+use crate::metrics;
+use actix_http::body::{BodySize, MessageBody, ResponseBody};
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse};
+use actix_web::http::{header, HeaderValue};
+use actix_web::web::Bytes;
+use actix_web::{Error, HttpResponse};
+use std::collections::HashMap;
+use std::future::Future;
+use std::net::IpAddr;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, RwLock};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+/// Number of tracked clients above which idle ones are forgotten
+const MAX_CLIENTS: usize = 10_000;
+
+/// Minimum time between two scans for idle clients
+const EVICT_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Network in CIDR notation, e.g. `10.0.0.0/8` or `fd00::/8`. A plain address is a network
+/// of its own.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr
+            .parse()
+            .map_err(|_| format!("Invalid address {:?}", addr))?;
+        let bits = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix
+                .parse()
+                .ok()
+                .filter(|prefix| *prefix <= bits)
+                .ok_or_else(|| format!("Invalid prefix length {:?}", prefix))?,
+            None => bits,
+        };
+
+        Ok(Cidr { addr, prefix })
+    }
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                same_prefix(u32::from(net).into(), u32::from(ip).into(), 32, self.prefix)
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                same_prefix(u128::from(net), u128::from(ip), 128, self.prefix)
+            }
+            _ => false,
+        }
+    }
+}
+
+fn same_prefix(a: u128, b: u128, bits: u8, prefix: u8) -> bool {
+    let shift = u32::from(bits - prefix);
+    a.checked_shr(shift).unwrap_or(0) == b.checked_shr(shift).unwrap_or(0)
+}
+
+/// Why a request was rejected
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Limited {
+    /// The client sent more requests than its rate allows, it may retry after the duration
+    Rate(Duration),
+    /// The client has too many requests in flight
+    Connections,
+}
+
+impl Limited {
+    pub fn reason(&self) -> &'static str {
+        match self {
+            Limited::Rate(_) => "rate_limited",
+            Limited::Connections => "too_many_connections",
+        }
+    }
+
+    /// Seconds for the `Retry-After` header, at least one
+    pub fn retry_after(&self) -> u64 {
+        match self {
+            Limited::Rate(wait) => wait.as_secs_f64().ceil().max(1.0) as u64,
+            Limited::Connections => 1,
+        }
+    }
+}
+
+struct Client {
+    tokens: f64,
+    refilled: Instant,
+    active: u32,
+}
+
+/// Tracked clients of a limiter and when idle ones were last forgotten
+struct Clients {
+    clients: HashMap<IpAddr, Client>,
+    evicted: Instant,
+}
+
+/// Request rate and connection limits of each client IP address for a server or an entry
+pub struct ClientLimiter {
+    config: ClientLimitsConfig,
+    trusted: Vec<Cidr>,
+    clients: Mutex<Clients>,
+}
+
+impl ClientLimiter {
+    /// Limiter for `config`, if it sets any limit. Invalid networks are rejected by the
+    /// config validation and skipped here.
+    pub fn new(config: &ClientLimitsConfig) -> Option<Arc<Self>> {
+        if config.rate.is_none() && config.connections.is_none() {
+            return None;
+        }
+
+        Some(Arc::new(ClientLimiter {
+            config: config.clone(),
+            trusted: config
+                .trusted
+                .iter()
+                .filter_map(|net| net.parse().ok())
+                .collect(),
+            clients: Mutex::new(Clients {
+                clients: HashMap::new(),
+                evicted: Instant::now(),
+            }),
+        }))
+    }
+
+    fn is_trusted(&self, ip: IpAddr) -> bool {
+        self.trusted.iter().any(|net| net.contains(ip))
+    }
+
+    /// Whether `ip` is within its limits at `now`, without counting the request yet
+    fn check(&self, clients: &mut Clients, ip: IpAddr, now: Instant) -> Result<(), Limited> {
+        let burst = self.burst();
+        if clients.clients.len() >= MAX_CLIENTS
+            && now.saturating_duration_since(clients.evicted) >= EVICT_INTERVAL
+        {
+            clients
+                .clients
+                .retain(|_, client| client.active > 0 || self.refill(client, now) < burst);
+            clients.evicted = now;
+        }
+        let client = clients.clients.entry(ip).or_insert(Client {
+            tokens: burst,
+            refilled: now,
+            active: 0,
+        });
+
+        if let Some(connections) = self.config.connections {
+            if client.active >= connections {
+                return Err(Limited::Connections);
+            }
+        }
+
+        if let Some(rate) = self.config.rate {
+            client.tokens = self.refill(client, now);
+            client.refilled = now;
+            if client.tokens < 1.0 {
+                let wait = (1.0 - client.tokens) / f64::from(rate);
+                return Err(Limited::Rate(Duration::from_secs_f64(wait)));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Counts the request of `ip` after it passed `check`
+    fn take(self: &Arc<Self>, clients: &mut Clients, ip: IpAddr) -> Option<Slot> {
+        let client = clients.clients.get_mut(&ip)?;
+
+        if self.config.rate.is_some() {
+            client.tokens -= 1.0;
+        }
+
+        if self.config.connections.is_some() {
+            client.active += 1;
+            Some(Slot {
+                limiter: self.clone(),
+                ip,
+            })
+        } else {
+            None
+        }
+    }
+
+    fn burst(&self) -> f64 {
+        f64::from(self.config.burst.or(self.config.rate).unwrap_or(1))
+    }
+
+    /// Tokens of `client` at `now`
+    fn refill(&self, client: &Client, now: Instant) -> f64 {
+        let rate = f64::from(self.config.rate.unwrap_or(0));
+        let elapsed = now.saturating_duration_since(client.refilled).as_secs_f64();
+        (client.tokens + elapsed * rate).min(self.burst())
+    }
+}
+
+/// Connection of a client, released when dropped
+pub struct Slot {
+    limiter: Arc<ClientLimiter>,
+    ip: IpAddr,
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        let mut clients = self.limiter.clients.lock().unwrap();
+        if let Some(client) = clients.clients.get_mut(&self.ip) {
+            client.active = client.active.saturating_sub(1);
+        }
+    }
+}
+
+/// Limiters of a server and its entries, updated when the config is reloaded
+pub struct Limiters {
+    /// Networks of the server exempt from all limits, including the ones of the entries
+    trusted: RwLock<Vec<Cidr>>,
+    server: RwLock<Option<Arc<ClientLimiter>>>,
+    entries: RwLock<HashMap<String, Arc<ClientLimiter>>>,
+}
+
+impl Limiters {
+    pub fn new(server: &ClientLimitsConfig, config: &Config) -> Self {
+        let limiters = Limiters {
+            trusted: RwLock::new(Vec::new()),
+            server: RwLock::new(None),
+            entries: RwLock::new(HashMap::new()),
+        };
+        limiters.reload(server, config);
+        limiters
+    }
+
+    /// Applies a reloaded config, keeping the state of unchanged limiters
+    pub fn reload(&self, server: &ClientLimitsConfig, config: &Config) {
+        *self.trusted.write().unwrap() = server
+            .trusted
+            .iter()
+            .filter_map(|net| net.parse().ok())
+            .collect();
+
+        let mut current = self.server.write().unwrap();
+        if current.as_ref().map(|limiter| &limiter.config) != Some(server) {
+            *current = ClientLimiter::new(server);
+        }
+
+        let mut entries = self.entries.write().unwrap();
+        let old = std::mem::take(&mut *entries);
+        for (name, entry) in config.entries.iter() {
+            let limiter = match old.get(name) {
+                Some(limiter) if limiter.config == entry.client_limits => Some(limiter.clone()),
+                _ => ClientLimiter::new(&entry.client_limits),
+            };
+            if let Some(limiter) = limiter {
+                entries.insert(name.clone(), limiter);
+            }
+        }
+    }
+
+    /// Counts the request against the limits of the server and of the entry it is for,
+    /// returning the slots to hold while it is served. It is only counted if it passes all
+    /// of them.
+    pub fn admit(&self, req: &ServiceRequest) -> Result<Vec<Slot>, Limited> {
+        let ip = match req.peer_addr() {
+            Some(addr) => addr.ip(),
+            None => return Ok(Vec::new()),
+        };
+        if self
+            .trusted
+            .read()
+            .unwrap()
+            .iter()
+            .any(|net| net.contains(ip))
+        {
+            return Ok(Vec::new());
+        }
+
+        let entry = entry_name(req.path())
+            .and_then(|entry| self.entries.read().unwrap().get(entry).cloned());
+        let server = self.server.read().unwrap().clone();
+
+        let limiters: Vec<_> = server.iter().chain(entry.iter()).collect();
+        admit(&limiters, ip, Instant::now())
+    }
+}
+
+/// Counts a request of `ip` against all `limiters` if it is within the limits of each, the
+/// returned slots hold the client's connections until they are dropped
+fn admit(limiters: &[&Arc<ClientLimiter>], ip: IpAddr, now: Instant) -> Result<Vec<Slot>, Limited> {
+    let limiters: Vec<_> = limiters
+        .iter()
+        .filter(|limiter| !limiter.is_trusted(ip))
+        .collect();
+    // Always locked in the given order, the server before the entry
+    let mut locked: Vec<_> = limiters
+        .iter()
+        .map(|limiter| limiter.clients.lock().unwrap())
+        .collect();
+
+    for (limiter, clients) in limiters.iter().zip(locked.iter_mut()) {
+        limiter.check(clients, ip, now)?;
+    }
+
+    Ok(limiters
+        .iter()
+        .zip(locked.iter_mut())
+        .filter_map(|(limiter, clients)| limiter.take(clients, ip))
+        .collect())
+}
+
+/// Name of the entry in the path of a file request, `/c/v1/{entry}/f/{filename}`
+fn entry_name(path: &str) -> Option<&str> {
+    let (entry, rest) = path.strip_prefix("/c/v1/")?.split_once('/')?;
+    rest.strip_prefix("f/").map(|_| entry)
+}
+
+/// Passes the request on to `srv` if the client is within its limits, otherwise answers it
+/// with `429 Too Many Requests` built by `response` from the reason and a `Retry-After`
+/// header. The client's connections are held until the response body is sent.
+pub fn limit<S, B, F>(
+    limiters: &Limiters,
+    req: ServiceRequest,
+    srv: &mut S,
+    response: F,
+) -> impl Future<Output = Result<ServiceResponse<Held<B>>, Error>>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    B: MessageBody,
+    F: FnOnce(&'static str) -> HttpResponse,
+{
+    let admitted = match limiters.admit(&req) {
+        Ok(slots) => Ok((srv.call(req), slots)),
+        Err(limited) => {
+            metrics::LIMITED_REQUESTS
+                .with_label_values(&[limited.reason()])
+                .inc();
+            let mut res = response(limited.reason());
+            res.headers_mut().insert(
+                header::RETRY_AFTER,
+                HeaderValue::from(limited.retry_after()),
+            );
+            Err(req.into_response(res.into_body()))
+        }
+    };
+
+    async move {
+        match admitted {
+            Ok((fut, slots)) => Ok(fut.await?.map_body(|_, body| {
+                ResponseBody::Body(Held {
+                    body: Box::pin(body),
+                    _slots: slots,
+                })
+            })),
+            Err(res) => Ok(res),
+        }
+    }
+}
+
+/// Response body holding the connection slots of the client until it is sent
+pub struct Held<B> {
+    body: Pin<Box<ResponseBody<B>>>,
+    _slots: Vec<Slot>,
+}
+
+impl<B: MessageBody> MessageBody for Held<B> {
+    fn size(&self) -> BodySize {
+        self.body.size()
+    }
+
+    fn poll_next(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Bytes, Error>>> {
+        self.body.as_mut().poll_next(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cidr() {
+        let net: Cidr = "10.1.0.0/16".parse().unwrap();
+        assert!(net.contains("10.1.2.3".parse().unwrap()));
+        assert!(net.contains("::ffff:10.1.2.3".parse().unwrap()));
+        assert!(!net.contains("10.2.0.1".parse().unwrap()));
+
+        let any: Cidr = "::/0".parse().unwrap();
+        assert!(any.contains("2001:db8::1".parse().unwrap()));
+        assert!(!any.contains("127.0.0.1".parse().unwrap()));
+
+        let host: Cidr = "192.168.0.1".parse().unwrap();
+        assert!(host.contains("192.168.0.1".parse().unwrap()));
+        assert!(!host.contains("192.168.0.2".parse().unwrap()));
+
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+        assert!("localhost".parse::<Cidr>().is_err());
+    }
+
+    #[test]
+    fn test_client_limiter() {
+        let limiter = ClientLimiter::new(&ClientLimitsConfig {
+            rate: Some(2),
+            burst: None,
+            connections: Some(2),
+            trusted: vec!["127.0.0.0/8".to_owned()],
+        })
+        .unwrap();
+        let admit = |ip, now| admit(&[&limiter], ip, now);
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        let now = Instant::now();
+
+        let first = admit(ip, now).unwrap();
+        let second = admit(ip, now).unwrap();
+        assert_eq!(admit(ip, now).err(), Some(Limited::Connections));
+        drop((first, second));
+
+        // Both requests of the burst are used up
+        let later = now + Duration::from_millis(250);
+        assert_eq!(
+            admit(ip, later).err(),
+            Some(Limited::Rate(Duration::from_millis(250)))
+        );
+        assert!(admit(ip, now + Duration::from_secs(1)).is_ok());
+
+        let local = "127.0.0.1".parse().unwrap();
+        for _ in 0..10 {
+            assert!(admit(local, now).unwrap().is_empty());
+        }
+    }
+
+    #[test]
+    fn test_admit_all_or_nothing() {
+        let limiter = |rate, connections| {
+            ClientLimiter::new(&ClientLimitsConfig {
+                rate,
+                burst: None,
+                connections,
+                trusted: Vec::new(),
+            })
+            .unwrap()
+        };
+        let (server, entry) = (limiter(Some(1), None), limiter(None, Some(1)));
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        let now = Instant::now();
+
+        let held = admit(&[&entry], ip, now).unwrap();
+        assert_eq!(
+            admit(&[&server, &entry], ip, now).err(),
+            Some(Limited::Connections)
+        );
+        drop(held);
+
+        // The rejected request did not use up the only token of the server
+        assert_eq!(admit(&[&server, &entry], ip, now).unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,41 @@
+use std::path::{Component, Path};
+
+/// Whether `name` can be stored as a file directly in the entry's directory. Globs match
+/// across `/`, so names from requests and origin listings are checked before they are
+/// joined to the path. Hidden names are reserved for staged files and the index.
+pub fn is_valid(name: &str) -> bool {
+    let mut components = Path::new(name).components();
+
+    !name.is_empty()
+        && !name.starts_with('.')
+        && !name.contains(&['/', '\\'][..])
+        && matches!(components.next(), Some(Component::Normal(_)))
+        && components.next().is_none()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_valid() {
+        assert!(is_valid("episode 1.m4a"));
+        assert!(is_valid("a.b.m4a"));
+        assert!(is_valid("track..m4a"));
+
+        for name in &[
+            "",
+            ".",
+            "..",
+            "../x",
+            "../../x.m4a",
+            "a/b.m4a",
+            "/etc/x.m4a",
+            "a\\b.m4a",
+            ".index",
+            ".x.m4a.download",
+        ] {
+            assert!(!is_valid(name), "{:?}", name);
+        }
+    }
+}
@@ -33,12 +33,19 @@ where
         {
             let hash_bytes =
                 hex::decode(data).map_err(|_e| Error::custom("Failed to decode hex"))?;
-            let hash_array: [u8; blake3::OUT_LEN] = hash_bytes[..]
-                .try_into()
-                .map_err(|_e| Error::custom("Invalid length"))?;
-            Ok(hash_array.into())
+            from_bytes(&hash_bytes).ok_or_else(|| Error::custom("Invalid length"))
         }
     }
 
     deserializer.deserialize_str(HashStrVisitor)
 }
+
+/// Parses a hex encoded hash
+pub fn from_hex(data: &str) -> Option<Hash> {
+    from_bytes(&hex::decode(data).ok()?)
+}
+
+fn from_bytes(data: &[u8]) -> Option<Hash> {
+    let hash_array: [u8; blake3::OUT_LEN] = data.try_into().ok()?;
+    Some(hash_array.into())
+}
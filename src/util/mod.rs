@@ -1,4 +1,14 @@
+pub mod access_log;
+pub mod bearer;
 mod chunked_read_file;
+pub mod client_limits;
+pub mod file_name;
 pub mod hash_serde;
+mod multipart;
 pub mod named_file;
 mod range;
+#[cfg(test)]
+pub mod test_dir;
+#[cfg(test)]
+pub mod test_server;
+pub mod throttle;
@@ -0,0 +1,145 @@
+use std::fs::File;
+use std::io;
+
+use actix_web::web::Bytes;
+use actix_web::Error;
+use futures_util::future::ready;
+use futures_util::stream::{self, LocalBoxStream, StreamExt};
+use prometheus::IntCounter;
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+
+use super::chunked_read_file::ChunkedReadFile;
+use super::range::HttpRange;
+use super::throttle::Throttle;
+
+/// Body of a `multipart/byteranges` response as described in RFC 7233, Appendix A.
+pub struct MultipartRanges {
+    boundary: String,
+    parts: Vec<(Bytes, HttpRange)>,
+    trailer: Bytes,
+}
+
+impl MultipartRanges {
+    pub fn new(ranges: &[HttpRange], content_type: &str, size: u64) -> Self {
+        let boundary: String = rand::thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(24)
+            .collect();
+
+        let parts = ranges
+            .iter()
+            .map(|range| {
+                let head = format!(
+                    "\r\n--{}\r\nContent-Type: {}\r\nContent-Range: bytes {}-{}/{}\r\n\r\n",
+                    boundary,
+                    content_type,
+                    range.start,
+                    range.start + range.length - 1,
+                    size
+                );
+                (Bytes::from(head), *range)
+            })
+            .collect();
+
+        let trailer = Bytes::from(format!("\r\n--{}--\r\n", boundary));
+
+        MultipartRanges {
+            boundary,
+            parts,
+            trailer,
+        }
+    }
+
+    /// `Content-Type` header of the whole response
+    pub fn content_type(&self) -> String {
+        format!("multipart/byteranges; boundary={}", self.boundary)
+    }
+
+    /// Total length of the body in bytes
+    pub fn length(&self) -> u64 {
+        let parts: u64 = self
+            .parts
+            .iter()
+            .map(|(head, range)| head.len() as u64 + range.length)
+            .sum();
+
+        parts + self.trailer.len() as u64
+    }
+
+    pub fn into_stream(
+        self,
+        file: File,
+        bytes_counter: Option<IntCounter>,
+        throttle: Throttle,
+    ) -> io::Result<LocalBoxStream<'static, Result<Bytes, Error>>> {
+        let mut streams: Vec<LocalBoxStream<'static, Result<Bytes, Error>>> = Vec::new();
+
+        for (head, range) in self.parts {
+            streams.push(stream::once(ready(Ok(head))).boxed_local());
+            streams.push(
+                ChunkedReadFile {
+                    offset: range.start,
+                    size: range.length,
+                    file: Some(file.try_clone()?),
+                    fut: None,
+                    counter: 0,
+                    bytes_counter: bytes_counter.clone(),
+                    throttle: throttle.clone(),
+                    delay: None,
+                }
+                .boxed_local(),
+            );
+        }
+
+        streams.push(stream::once(ready(Ok(self.trailer))).boxed_local());
+
+        Ok(stream::iter(streams).flatten().boxed_local())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::test_dir::TestDir;
+    use std::fs;
+
+    #[test]
+    fn test_body_length() {
+        let dir = TestDir::new("multipart");
+        let path = dir.join("body");
+        fs::write(&path, b"0123456789").unwrap();
+
+        let ranges = [
+            HttpRange {
+                start: 0,
+                length: 2,
+            },
+            HttpRange {
+                start: 7,
+                length: 3,
+            },
+        ];
+        let body = MultipartRanges::new(&ranges, "text/plain", 10);
+        let boundary = body.boundary.clone();
+        let length = body.length();
+
+        let stream = body
+            .into_stream(File::open(&path).unwrap(), None, Throttle::default())
+            .unwrap();
+        let chunks = actix_rt::System::new("test").block_on(stream.collect::<Vec<_>>());
+        let got: Vec<u8> = chunks
+            .into_iter()
+            .flat_map(|chunk| chunk.unwrap().to_vec())
+            .collect();
+
+        let expected = format!(
+            "\r\n--{b}\r\nContent-Type: text/plain\r\nContent-Range: bytes 0-1/10\r\n\r\n01\
+             \r\n--{b}\r\nContent-Type: text/plain\r\nContent-Range: bytes 7-9/10\r\n\r\n789\
+             \r\n--{b}--\r\n",
+            b = boundary
+        );
+        assert_eq!(String::from_utf8(got).unwrap(), expected);
+        assert_eq!(length, expected.len() as u64);
+    }
+}
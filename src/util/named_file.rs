@@ -1,34 +1,42 @@
 use std::fs::{File, Metadata};
 use std::io;
 use std::ops::{Deref, DerefMut};
-use std::path::{Path, PathBuf};
+use std::path::Path;
 use std::time::{SystemTime, UNIX_EPOCH};
 
 #[cfg(unix)]
 use std::os::unix::fs::MetadataExt;
 
 use actix_http::body::SizedStream;
-use actix_web::dev::BodyEncoding;
-use actix_web::http::header::{
-    self, Charset, ContentDisposition, DispositionParam, DispositionType, ExtendedValue,
-};
+use actix_web::dev::{BodyEncoding, HttpResponseBuilder};
+use actix_web::http::header;
 use actix_web::http::{ContentEncoding, StatusCode};
 use actix_web::{Error, HttpMessage, HttpRequest, HttpResponse, Responder};
 use futures_util::future::{ready, Ready};
+use prometheus::IntCounter;
 
-use super::range::HttpRange;
 use super::chunked_read_file::ChunkedReadFile;
+use super::multipart::MultipartRanges;
+use super::range::HttpRange;
+use super::throttle::Throttle;
+
+pub const DEFAULT_MAX_RANGES: usize = 16;
 
 /// A file with an associated name.
 #[derive(Debug)]
 pub struct NamedFile {
-    path: PathBuf,
     file: File,
     modified: Option<SystemTime>,
+    etag: Option<header::EntityTag>,
+    last_modified: Option<header::HttpDate>,
     pub(crate) md: Metadata,
     pub(crate) status_code: StatusCode,
     pub(crate) content_type: String,
+    pub(crate) headers: Vec<(String, String)>,
     pub(crate) encoding: Option<ContentEncoding>,
+    pub(crate) max_ranges: usize,
+    pub(crate) bytes_counter: Option<IntCounter>,
+    pub(crate) throttle: Throttle,
 }
 
 impl NamedFile {
@@ -54,48 +62,29 @@ impl NamedFile {
     /// }
     /// ```
     pub fn from_file<P: AsRef<Path>>(file: File, path: P) -> io::Result<NamedFile> {
-        let path = path.as_ref().to_path_buf();
-
-        // Get the name of the file and use it to construct default Content-Type
-        // and Content-Disposition values
-        let (content_type, content_disposition) = {
-            let filename = match path.file_name() {
-                Some(name) => name.to_string_lossy(),
-                None => {
-                    return Err(io::Error::new(
-                        io::ErrorKind::InvalidInput,
-                        "Provided path has no filename",
-                    ));
-                }
-            };
-
-            let disposition = DispositionType::Attachment;
-            let mut parameters =
-                vec![DispositionParam::Filename(String::from(filename.as_ref()))];
-            if !filename.is_ascii() {
-                parameters.push(DispositionParam::FilenameExt(ExtendedValue {
-                    charset: Charset::Ext(String::from("UTF-8")),
-                    language_tag: None,
-                    value: filename.into_owned().into_bytes(),
-                }))
-            }
-            let cd = ContentDisposition {
-                disposition,
-                parameters,
-            };
-            ("application/octet-stream".to_owned(), cd)
-        };
+        if path.as_ref().file_name().is_none() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Provided path has no filename",
+            ));
+        }
+        let content_type = "application/octet-stream".to_owned();
 
         let md = file.metadata()?;
         let modified = md.modified().ok();
         let encoding = None;
         Ok(NamedFile {
-            path,
             file,
             content_type,
+            headers: Vec::new(),
             md,
             modified,
+            etag: None,
+            last_modified: None,
             encoding,
+            max_ranges: DEFAULT_MAX_RANGES,
+            bytes_counter: None,
+            throttle: Throttle::default(),
             status_code: StatusCode::OK,
         })
     }
@@ -113,38 +102,61 @@ impl NamedFile {
         Self::from_file(File::open(&path)?, path)
     }
 
-    /// Returns reference to the underlying `File` object.
-    #[inline]
-    pub fn file(&self) -> &File {
-        &self.file
+    /// Set the `Content-Type` of the response
+    pub fn set_content_type(mut self, content_type: &str) -> Self {
+        self.content_type = content_type.to_owned();
+        self
     }
 
-    /// Retrieve the path of this file.
-    ///
-    /// # Examples
-    ///
-    /// ```rust
-    /// # use std::io;
-    /// use actix_files::NamedFile;
-    ///
-    /// # fn path() -> io::Result<()> {
-    /// let file = NamedFile::open("test.txt")?;
-    /// assert_eq!(file.path().as_os_str(), "foo.txt");
-    /// # Ok(())
-    /// # }
-    /// ```
-    #[inline]
-    pub fn path(&self) -> &Path {
-        self.path.as_path()
+    /// Set the content encoding of the response, `Identity` prevents compression by the
+    /// `Compress` middleware
+    pub fn set_content_encoding(mut self, encoding: ContentEncoding) -> Self {
+        self.encoding = Some(encoding);
+        self
+    }
+
+    /// Set the maximum number of ranges that are served as `multipart/byteranges`, requests
+    /// exceeding it after coalescing are answered with the full content
+    pub fn set_max_ranges(mut self, max_ranges: usize) -> Self {
+        self.max_ranges = max_ranges;
+        self
+    }
+
+    /// Set a counter that is increased by the number of bytes sent
+    pub fn set_bytes_counter(mut self, counter: IntCounter) -> Self {
+        self.bytes_counter = Some(counter);
+        self
+    }
+
+    /// Limit the rate at which the file is sent
+    pub fn set_throttle(mut self, throttle: Throttle) -> Self {
+        self.throttle = throttle;
+        self
     }
 
-    /// Set response **Status Code**
-    pub fn set_status_code(mut self, status: StatusCode) -> Self {
-        self.status_code = status;
+    /// Set additional headers that are sent with the response
+    pub fn set_headers(mut self, headers: Vec<(String, String)>) -> Self {
+        self.headers = headers;
+        self
+    }
+
+    /// Set the `ETag` instead of deriving it from the file metadata
+    pub fn set_etag(mut self, etag: header::EntityTag) -> Self {
+        self.etag = Some(etag);
+        self
+    }
+
+    /// Set the `Last-Modified` date instead of using the modification time of the file
+    pub fn set_last_modified(mut self, last_modified: header::HttpDate) -> Self {
+        self.last_modified = Some(last_modified);
         self
     }
 
     pub(crate) fn etag(&self) -> Option<header::EntityTag> {
+        if let Some(ref etag) = self.etag {
+            return Some(etag.clone());
+        }
+
         // This etag format is similar to Apache's.
         self.modified.as_ref().map(|mtime| {
             let ino = {
@@ -172,15 +184,15 @@ impl NamedFile {
     }
 
     pub(crate) fn last_modified(&self) -> Option<header::HttpDate> {
-        self.modified.map(|mtime| mtime.into())
+        self.last_modified
+            .or_else(|| self.modified.map(|mtime| mtime.into()))
     }
 
     pub fn into_response(self, req: &HttpRequest) -> Result<HttpResponse, Error> {
         if self.status_code != StatusCode::OK {
             let mut resp = HttpResponse::build(self.status_code);
-            // resp.set(header::ContentType(self.content_type.into()))
-            //     .set(header::ContentDisposition(self.content_disposition));
-            
+            self.set_content_headers(&mut resp);
+
             if let Some(current_encoding) = self.encoding {
                 resp.encoding(current_encoding);
             }
@@ -190,6 +202,9 @@ impl NamedFile {
                 file: Some(self.file),
                 fut: None,
                 counter: 0,
+                bytes_counter: self.bytes_counter,
+                throttle: self.throttle.clone(),
+                delay: None,
             };
             return Ok(resp.streaming(reader));
         }
@@ -203,8 +218,8 @@ impl NamedFile {
         } else if let (Some(ref m), Some(header::IfUnmodifiedSince(ref since))) =
             (last_modified, req.get_header())
         {
-            let t1: SystemTime = m.clone().into();
-            let t2: SystemTime = since.clone().into();
+            let t1: SystemTime = (*m).into();
+            let t2: SystemTime = (*since).into();
             match (t1.duration_since(UNIX_EPOCH), t2.duration_since(UNIX_EPOCH)) {
                 (Ok(t1), Ok(t2)) => t1 > t2,
                 _ => false,
@@ -221,8 +236,8 @@ impl NamedFile {
         } else if let (Some(ref m), Some(header::IfModifiedSince(ref since))) =
             (last_modified, req.get_header())
         {
-            let t1: SystemTime = m.clone().into();
-            let t2: SystemTime = since.clone().into();
+            let t1: SystemTime = (*m).into();
+            let t2: SystemTime = (*since).into();
             match (t1.duration_since(UNIX_EPOCH), t2.duration_since(UNIX_EPOCH)) {
                 (Ok(t1), Ok(t2)) => t1 <= t2,
                 _ => false,
@@ -232,44 +247,39 @@ impl NamedFile {
         };
 
         let mut resp = HttpResponse::build(self.status_code);
-            // resp.set(header::ContentType(self.content_type.into()))
-            //     .set(header::ContentDisposition(self.content_disposition));
+        self.set_content_headers(&mut resp);
 
         // default compressing
         if let Some(current_encoding) = self.encoding {
             resp.encoding(current_encoding);
         }
 
-        resp.if_some(last_modified, |lm, resp| {
+        if let Some(lm) = last_modified {
             resp.set(header::LastModified(lm));
-        })
-        .if_some(etag, |etag, resp| {
+        }
+        if let Some(etag) = etag {
             resp.set(header::ETag(etag));
-        });
+        }
 
         resp.header(header::ACCEPT_RANGES, "bytes");
 
-        let mut length = self.md.len();
-        let mut offset = 0;
+        let size = self.md.len();
+        let mut ranges = Vec::new();
 
         // check for range header
-        if let Some(ranges) = req.headers().get(&header::RANGE) {
-            if let Ok(rangesheader) = ranges.to_str() {
-                if let Ok(rangesvec) = HttpRange::parse(rangesheader, length) {
-                    length = rangesvec[0].length;
-                    offset = rangesvec[0].start;
-                    resp.encoding(ContentEncoding::Identity);
-                    resp.header(
-                        header::CONTENT_RANGE,
-                        format!(
-                            "bytes {}-{}/{}",
-                            offset,
-                            offset + length - 1,
-                            self.md.len()
-                        ),
-                    );
+        if let Some(rangesheader) = req.headers().get(&header::RANGE) {
+            if let Ok(rangesheader) = rangesheader.to_str() {
+                if let Ok(rangesvec) = HttpRange::parse(rangesheader, size) {
+                    ranges = HttpRange::coalesce(rangesvec);
+
+                    // Too many distinct ranges, ignore the header and respond with the full
+                    // content instead
+                    if ranges.len() > self.max_ranges {
+                        log::debug!("Ignoring request for {} ranges", ranges.len());
+                        ranges.clear();
+                    }
                 } else {
-                    resp.header(header::CONTENT_RANGE, format!("bytes */{}", length));
+                    resp.header(header::CONTENT_RANGE, format!("bytes */{}", size));
                     return Ok(resp.status(StatusCode::RANGE_NOT_SATISFIABLE).finish());
                 };
             } else {
@@ -283,19 +293,68 @@ impl NamedFile {
             return Ok(resp.status(StatusCode::NOT_MODIFIED).finish());
         }
 
-        let reader = ChunkedReadFile {
-            offset,
-            size: length,
-            file: Some(self.file),
-            fut: None,
-            counter: 0,
-        };
+        match ranges.len() {
+            0 => {
+                let reader = ChunkedReadFile {
+                    offset: 0,
+                    size,
+                    file: Some(self.file),
+                    fut: None,
+                    counter: 0,
+                    bytes_counter: self.bytes_counter,
+                    throttle: self.throttle.clone(),
+                    delay: None,
+                };
+
+                Ok(resp.body(SizedStream::new(size, reader)))
+            }
+            1 => {
+                let HttpRange { start, length } = ranges[0];
+
+                resp.encoding(ContentEncoding::Identity);
+                resp.header(
+                    header::CONTENT_RANGE,
+                    format!("bytes {}-{}/{}", start, start + length - 1, size),
+                );
+
+                let reader = ChunkedReadFile {
+                    offset: start,
+                    size: length,
+                    file: Some(self.file),
+                    fut: None,
+                    counter: 0,
+                    bytes_counter: self.bytes_counter,
+                    throttle: self.throttle.clone(),
+                    delay: None,
+                };
+
+                if start != 0 || length != size {
+                    resp.status(StatusCode::PARTIAL_CONTENT);
+                }
+
+                Ok(resp.body(SizedStream::new(length, reader)))
+            }
+            _ => {
+                let body = MultipartRanges::new(&ranges, &self.content_type, size);
+
+                resp.encoding(ContentEncoding::Identity);
+                resp.set_header(header::CONTENT_TYPE, body.content_type());
+                resp.status(StatusCode::PARTIAL_CONTENT);
 
-        if offset != 0 || length != self.md.len() {
-            resp.status(StatusCode::PARTIAL_CONTENT);
+                let length = body.length();
+                let reader = body.into_stream(self.file, self.bytes_counter, self.throttle)?;
+
+                Ok(resp.body(SizedStream::new(length, reader)))
+            }
         }
+    }
+
+    fn set_content_headers(&self, resp: &mut HttpResponseBuilder) {
+        resp.header(header::CONTENT_TYPE, self.content_type.as_str());
 
-        Ok(resp.body(SizedStream::new(length, reader)))
+        for (name, value) in self.headers.iter() {
+            resp.header(name.as_str(), value.as_str());
+        }
     }
 }
 
@@ -1,3 +1,5 @@
+use std::cmp;
+
 /// HTTP Range header representation.
 #[derive(Debug, Clone, Copy)]
 pub struct HttpRange {
@@ -42,6 +44,11 @@ impl HttpRange {
                     if length > size_sig {
                         length = size_sig;
                     }
+                    if length == 0 {
+                        // An empty suffix selects nothing and cannot be satisfied.
+                        no_overlap = true;
+                        return Ok(None);
+                    }
 
                     Ok(Some(HttpRange {
                         start: (size_sig - length) as u64,
@@ -83,7 +90,7 @@ impl HttpRange {
             })
             .collect::<Result<_, _>>()?;
 
-        let ranges: Vec<HttpRange> = all_ranges.into_iter().filter_map(|x| x).collect();
+        let ranges: Vec<HttpRange> = all_ranges.into_iter().flatten().collect();
 
         if no_overlap && ranges.is_empty() {
             return Err(());
@@ -91,6 +98,28 @@ impl HttpRange {
 
         Ok(ranges)
     }
+
+    /// Sorts the given ranges and merges all overlapping or adjacent ones.
+    pub fn coalesce(mut ranges: Vec<HttpRange>) -> Vec<HttpRange> {
+        ranges.sort_by_key(|r| r.start);
+
+        let mut res: Vec<HttpRange> = Vec::with_capacity(ranges.len());
+
+        for range in ranges {
+            if let Some(last) = res.last_mut() {
+                let last_end = last.start + last.length;
+                if range.start <= last_end {
+                    let end = cmp::max(last_end, range.start + range.length);
+                    last.length = end - last.start;
+                    continue;
+                }
+            }
+
+            res.push(range);
+        }
+
+        res
+    }
 }
 
 #[cfg(test)]
@@ -326,45 +355,31 @@ mod tests {
 
             let res = HttpRange::parse(header, size);
 
-            if res.is_err() {
-                if expected.is_empty() {
-                    continue;
-                } else {
-                    assert!(
-                        false,
-                        "parse({}, {}) returned error {:?}",
-                        header,
-                        size,
-                        res.unwrap_err()
-                    );
-                }
-            }
-
-            let got = res.unwrap();
+            let got = match res {
+                Ok(got) => got,
+                Err(_) if expected.is_empty() => continue,
+                Err(err) => panic!("parse({}, {}) returned error {:?}", header, size, err),
+            };
 
             if got.len() != expected.len() {
-                assert!(
-                    false,
+                panic!(
                     "len(parseRange({}, {})) = {}, want {}",
                     header,
                     size,
                     got.len(),
                     expected.len()
                 );
-                continue;
             }
 
             for i in 0..expected.len() {
                 if got[i].start != expected[i].start {
-                    assert!(
-                        false,
+                    panic!(
                         "parseRange({}, {})[{}].start = {}, want {}",
                         header, size, i, got[i].start, expected[i].start
                     )
                 }
                 if got[i].length != expected[i].length {
-                    assert!(
-                        false,
+                    panic!(
                         "parseRange({}, {})[{}].length = {}, want {}",
                         header, size, i, got[i].length, expected[i].length
                     )
@@ -372,4 +387,41 @@ mod tests {
             }
         }
     }
+
+    #[test]
+    fn test_parse_zero_length() {
+        assert!(HttpRange::parse("bytes=-0", 10).is_err());
+        assert!(HttpRange::parse("bytes=-5", 0).is_err());
+
+        let ranges = HttpRange::parse("bytes=-0,2-3", 10).unwrap();
+        assert_eq!(ranges.len(), 1);
+        assert_eq!((ranges[0].start, ranges[0].length), (2, 2));
+    }
+
+    #[test]
+    fn test_coalesce() {
+        let r = |start, length| HttpRange { start, length };
+
+        let tests = vec![
+            (vec![], vec![]),
+            (vec![r(0, 10)], vec![r(0, 10)]),
+            // Disjoint ranges are only sorted
+            (vec![r(20, 5), r(0, 10)], vec![r(0, 10), r(20, 5)]),
+            // Adjacent ranges are merged
+            (vec![r(0, 10), r(10, 5)], vec![r(0, 15)]),
+            // Overlapping and contained ranges are merged
+            (vec![r(5, 10), r(0, 8), r(6, 2)], vec![r(0, 15)]),
+            (
+                vec![r(0, 1), r(2, 1), r(1, 1), r(9, 1)],
+                vec![r(0, 3), r(9, 1)],
+            ),
+        ];
+
+        for (input, expected) in tests {
+            let got = HttpRange::coalesce(input.clone());
+            let got: Vec<_> = got.iter().map(|r| (r.start, r.length)).collect();
+            let expected: Vec<_> = expected.iter().map(|r| (r.start, r.length)).collect();
+            assert_eq!(got, expected, "coalesce({:?})", input);
+        }
+    }
 }
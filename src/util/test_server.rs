@@ -0,0 +1,17 @@
+use std::io::{Read, Write};
+use std::net::TcpListener;
+use std::thread;
+
+/// Answers a single HTTP request on a local port with `response`, returning the port
+pub fn respond_once(response: &'static [u8]) -> u16 {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+
+    thread::spawn(move || {
+        let (mut conn, _) = listener.accept().unwrap();
+        let _ = conn.read(&mut [0; 4096]).unwrap();
+        conn.write_all(response).unwrap();
+    });
+
+    port
+}
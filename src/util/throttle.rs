@@ -0,0 +1,99 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Token bucket limiting a transfer to `rate` bytes per second, allowing bursts of up to one
+/// second worth of bytes
+#[derive(Debug)]
+pub struct TokenBucket {
+    rate: f64,
+    state: Mutex<(f64, Instant)>,
+}
+
+impl TokenBucket {
+    pub fn new(rate: u64) -> Self {
+        let rate = rate.max(1) as f64;
+        TokenBucket {
+            rate,
+            state: Mutex::new((rate, Instant::now())),
+        }
+    }
+
+    /// Takes `bytes` tokens from the bucket, which may go into debt, and returns how long to
+    /// wait before sending them
+    pub fn take(&self, bytes: u64) -> Duration {
+        self.take_at(bytes, Instant::now())
+    }
+
+    fn take_at(&self, bytes: u64, now: Instant) -> Duration {
+        let mut state = self.state.lock().unwrap();
+        let (ref mut tokens, ref mut refilled) = *state;
+
+        let elapsed = now.saturating_duration_since(*refilled).as_secs_f64();
+        *tokens = (*tokens + elapsed * self.rate).min(self.rate) - bytes as f64;
+        *refilled = now;
+
+        if *tokens < 0.0 {
+            Duration::from_secs_f64(-*tokens / self.rate)
+        } else {
+            Duration::from_secs(0)
+        }
+    }
+}
+
+/// Token buckets that a transfer has to pass together, e.g. the ones of its connection, its
+/// entry and the whole node
+#[derive(Clone, Debug, Default)]
+pub struct Throttle {
+    buckets: Vec<Arc<TokenBucket>>,
+}
+
+impl Throttle {
+    /// Adds a bucket if a rate is configured
+    pub fn with_bucket(mut self, bucket: Option<Arc<TokenBucket>>) -> Self {
+        self.buckets.extend(bucket);
+        self
+    }
+
+    /// Adds a bucket of its own for `rate`, if set
+    pub fn with_rate(self, rate: Option<u64>) -> Self {
+        self.with_bucket(bucket(rate))
+    }
+
+    /// Takes `bytes` from all buckets and returns how long to wait before sending them
+    pub fn take(&self, bytes: u64) -> Duration {
+        self.buckets
+            .iter()
+            .map(|bucket| bucket.take(bytes))
+            .max()
+            .unwrap_or_default()
+    }
+}
+
+/// Bucket for `rate` bytes per second, shared between transfers, if a rate is set
+pub fn bucket(rate: Option<u64>) -> Option<Arc<TokenBucket>> {
+    rate.map(|rate| Arc::new(TokenBucket::new(rate)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_token_bucket() {
+        let bucket = TokenBucket::new(1000);
+        let start = *bucket.state.lock().unwrap();
+        let now = start.1;
+
+        // The burst of one second passes right away
+        assert_eq!(bucket.take_at(1000, now), Duration::from_secs(0));
+        assert_eq!(bucket.take_at(500, now), Duration::from_millis(500));
+
+        // Refilled by 1000 bytes within a second, still 500 in debt after taking 1000
+        let later = now + Duration::from_secs(1);
+        assert_eq!(bucket.take_at(1000, later), Duration::from_millis(500));
+
+        // Never refills beyond the burst
+        let much_later = now + Duration::from_secs(60);
+        assert_eq!(bucket.take_at(1500, much_later), Duration::from_millis(500));
+    }
+}